@@ -0,0 +1,1550 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+
+use anyhow::{bail, Context};
+use arrayvec::ArrayVec;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use nbt::Value;
+
+use crate::bytecompressors::{ArchiveCompressor, ByteCompressor};
+use crate::chunk::{
+	legacy_from_palette, legacy_to_palette, pack_nibbles, unpack_nibbles, BlockStatesMut, ChunkVersion, LegacyBlocksMut,
+	LIGHT_KEYS,
+};
+use crate::context::{legacy_palette_entries, section_blocks, section_y, SectionContext, SectionHistory};
+use crate::dedup::{Occurrence, Repeats};
+use crate::delta::{apply_changes, changes_from, Base};
+use crate::derived::{self, StrippedDerived};
+use crate::integercoders::IntegerCoder;
+use crate::integertransformers::IntegerTransformer;
+use crate::palette::BlockStateDictionary;
+use crate::priors::Priors;
+use crate::region::Chunk;
+use crate::tree;
+use crate::util::{
+	biome_bits, pack_integers, palette_bits, read_signed_varint, read_varint, write_signed_varint, write_varint, Dimensions,
+	PackedIntegerArrayIter, Packing,
+};
+
+const MAGIC: &[u8; 4] = b"MWRA";
+const VERSION: u8 = 21;
+/// Set when heightmaps and light were left out to be recomputed
+const FLAG_STRIPPED_DERIVED: u8 = 1;
+/// Set when the archive only holds the chunks that changed since a base snapshot
+const FLAG_DELTA: u8 = 2;
+/// Light levels are coded as palette indices into 0 to 15
+const LIGHT_LEVELS: u32 = 16;
+/// Written instead of a uniform light level ahead of coded light
+const LIGHT_CODED: u8 = LIGHT_LEVELS as u8;
+/// Written instead of a uniform light level ahead of light kept as packed nibbles, when coding it wouldn't make it smaller
+const LIGHT_PACKED: u8 = LIGHT_CODED + 1;
+/// Written for light arrays that were already empty, which would otherwise look like placeholders
+const LIGHT_EMPTY: u8 = LIGHT_PACKED + 1;
+/// Written ahead of light arrays that occur more than once in the region, followed by the Occurrence and then,
+/// unless it's a reference to a kept array, LIGHT_CODED or LIGHT_PACKED and the array
+const LIGHT_REPEATED: u8 = LIGHT_EMPTY + 1;
+/// Written for light arrays emptied by strip_derived, which are recomputed
+const LIGHT_DERIVED: u8 = LIGHT_REPEATED + 1;
+
+// Archive layout:
+// - magic, version, flags, which compressor was used,
+//   the checksum of the base archive (for delta archives), chunk count
+// - for each chunk: region index, timestamp
+// - for delta archives: a bit per chunk, set for unchanged chunks that are taken from the base,
+//   then how much each unchanged chunk's LastUpdate and InhabitedTime changed;
+//   everything after this only covers the changed chunks
+// - the NBT of every chunk with block state palettes, block states, biomes and light emptied,
+//   encoded column by column across chunks, then the count and columns of the region's distinct block state palette entries,
+//   the priors of the blocks in the region (if any),
+//   then whether each emptied section with blocks to code repeats another one's palette and blocks (see Occurrence), followed by
+//   each chunk's positions of the sections whose blocks or biomes were already empty,
+//   and of the heightmaps emptied by strip_derived (if the archive was written with it),
+//   sections' palettes as indices into those entries, and coded biome indices and BlockLight and SkyLight levels
+//   (or the level of sections with only one, or the packed nibbles if coding is larger, or whether it's recomputed),
+//   for those that were emptied or were empty,
+//   with coded biomes and light that repeat an earlier array's written as references to it,
+//   and coded light using the same light of the section directly below it in the chunk as context
+//   (compressed together, as they are small or code to very little)
+// - for each chunk, for each emptied section in order that isn't a reference to a kept one:
+//   the local palette if it's a pre-flattening section,
+//   then the coded indices if there is more than one palette entry, preceded by the transformer's side information
+//   (and, in delta archives for sections that are in the base, whether they're coded as their changes from it)
+//
+// Sections keep an empty Palette (or block_states.palette) list and an empty BlockStates
+// (or block_states.data, or Blocks/Data/Add, or biomes.data, or BlockLight/SkyLight) array as a placeholder,
+// so the key order of the NBT is preserved and the decoder knows which sections to fill in.
+
+pub fn write_archive<
+	Transformer: IntegerTransformer,
+	LightTransformer: IntegerTransformer,
+	Coder: IntegerCoder,
+	LightCoder: IntegerCoder,
+>(
+	chunks: &[Chunk],
+	base: Option<&Base>,
+	dest: &mut impl Write,
+	compressor: &ArchiveCompressor,
+	priors: Option<&Priors>,
+	strip_derived: bool,
+) -> anyhow::Result<()> {
+	dest.write_all(MAGIC)?;
+	dest.write_u8(VERSION)?;
+	let mut flags = 0;
+	if strip_derived {
+		flags |= FLAG_STRIPPED_DERIVED;
+	}
+	if base.is_some() {
+		flags |= FLAG_DELTA;
+	}
+	dest.write_u8(flags)?;
+	dest.write_u8(compressor.id())?;
+	if let Some(base) = base {
+		dest.write_u32::<BigEndian>(base.checksum())?;
+	}
+	write_varint(dest, chunks.len() as u64)?;
+	for chunk in chunks {
+		dest.write_u16::<BigEndian>(chunk.index)?;
+		dest.write_u32::<BigEndian>(chunk.timestamp)?;
+	}
+
+	// Only the chunks that changed since the base are encoded
+	let unchanged: Vec<Option<[i64; 2]>> = chunks
+		.iter()
+		.map(|chunk| base.and_then(|base| base.unchanged(chunk)))
+		.collect();
+	if base.is_some() {
+		for bits in unchanged.chunks(8) {
+			dest.write_u8(
+				bits.iter()
+					.enumerate()
+					.fold(0, |byte, (i, unchanged)| byte | (unchanged.is_some() as u8) << i),
+			)?;
+		}
+		for tick_changes in unchanged.iter().flatten() {
+			for change in tick_changes {
+				write_signed_varint(dest, *change)?;
+			}
+		}
+	}
+	let chunks: Vec<&Chunk> = chunks
+		.iter()
+		.zip(&unchanged)
+		.filter(|(_, unchanged)| unchanged.is_none())
+		.map(|(chunk, _)| chunk)
+		.collect();
+
+	let mut nbt = vec![];
+	let mut dictionary = BlockStateDictionary::new();
+	let mut stripped_sections = vec![];
+	let mut stripped_arrays = vec![];
+	for chunk in &chunks {
+		let (data, stripped, arrays) = if strip_derived {
+			let mut data = chunk.data.clone();
+			let derived = derived::strip_derived(&mut data);
+			strip_chunk(&data, Some(&derived), &mut dictionary)?
+		} else {
+			strip_chunk(&chunk.data, None, &mut dictionary)?
+		};
+		nbt.push(data);
+		stripped_sections.push(stripped);
+		stripped_arrays.push(arrays);
+	}
+	let occurrences = section_occurrences(&chunks, &stripped_sections)?;
+	// Only the priors of block states in the region are stored
+	let priors = priors.map(|priors| {
+		let palettes: Vec<Vec<Value>> = chunks
+			.iter()
+			.flat_map(|chunk| {
+				let version = ChunkVersion::of(&chunk.data);
+				version
+					.sections(&chunk.data)
+					.into_iter()
+					.flatten()
+					.filter_map(move |section| section_blocks(version, section))
+					.map(|(_, palette, _)| palette)
+			})
+			.collect();
+		priors.for_palettes(palettes.iter().map(Vec::as_slice))
+	});
+
+	// Priors don't always save more than storing them costs, so they're only stored if the archive comes out smaller with them
+	let write_coded = |priors: Option<&Priors>| -> anyhow::Result<Vec<u8>> {
+		let mut dest = vec![];
+		let mut priors_bytes = vec![];
+		if let Some(priors) = priors {
+			priors.write(&mut priors_bytes)?;
+		}
+		let payload = region_payload::<Transformer, LightTransformer, Coder, LightCoder>(
+			&nbt,
+			&dictionary,
+			&priors_bytes,
+			&occurrences,
+			stripped_arrays.clone(),
+		)?;
+		write_payload(&mut dest, compressor, &payload)?;
+		write_sections::<Transformer, Coder>(&mut dest, compressor, &chunks, base, &stripped_sections, &occurrences, priors)?;
+		Ok(dest)
+	};
+	let mut coded = write_coded(None)?;
+	if let Some(priors) = &priors {
+		let coded_with_priors = write_coded(Some(priors))?;
+		if coded_with_priors.len() < coded.len() {
+			coded = coded_with_priors;
+		}
+	}
+	dest.write_all(&coded)?;
+	Ok(())
+}
+
+/// Codes the blocks of every stripped section
+fn write_sections<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	dest: &mut impl Write,
+	compressor: &ArchiveCompressor,
+	chunks: &[&Chunk],
+	base: Option<&Base>,
+	stripped_sections: &[Vec<StrippedSection>],
+	occurrences: &[Occurrence],
+	priors: Option<&Priors>,
+) -> anyhow::Result<()> {
+	// Already written sections are used as context, in the same order read_archive restores them
+	let mut history = SectionHistory::new();
+	let mut occurrences = occurrences.iter().copied();
+	for (chunk, stripped) in chunks.iter().zip(stripped_sections) {
+		let version = ChunkVersion::of(&chunk.data);
+		let mut stripped = stripped.iter().peekable();
+		for (i, section) in version.sections(&chunk.data).into_iter().flatten().enumerate() {
+			let blocks = section_blocks(version, section);
+			if let Some(stripped_section) = stripped.next_if(|stripped_section| stripped_section.section_index == i) {
+				let palette = stripped_section.palette(version, section);
+				let occurrence = if stripped_section.codes_blocks() {
+					occurrences.next().context("Section occurrence missing")?
+				} else {
+					Occurrence::Unique
+				};
+				// References restore the palette and blocks of the kept section, without coding them again
+				if !matches!(occurrence, Occurrence::Reference(_)) {
+					let mut context = history.context(chunk.index, section_y(section), &palette);
+					context.prior_counts = priors.map(|priors| priors.initial_counts(&palette));
+					let changes_context = base.and_then(|base| base.changes_context(&context, chunk.index, section_y(section)));
+					if let Some(palette) = &stripped_section.legacy_palette {
+						write_varint(dest, palette.len() as u64)?;
+						for state in palette {
+							write_varint(dest, *state as u64)?;
+						}
+					}
+					write_section_blocks::<Transformer, Coder>(
+						dest,
+						compressor,
+						stripped_section.arr,
+						stripped_section.palette_length,
+						&context,
+						changes_context.as_ref(),
+					)?;
+				}
+			}
+			if let Some((y, palette, arr)) = blocks {
+				history.insert(chunk.index, y, palette, arr);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Whether each stripped section that codes blocks repeats another one in the region, in the order they are written,
+/// so that only one copy of identical sections is coded
+fn section_occurrences(chunks: &[&Chunk], stripped_sections: &[Vec<StrippedSection>]) -> anyhow::Result<Vec<Occurrence>> {
+	let mut keys = vec![];
+	let mut repeats = Repeats::new();
+	for (chunk, stripped) in chunks.iter().zip(stripped_sections) {
+		let version = ChunkVersion::of(&chunk.data);
+		for stripped_section in stripped.iter().filter(|stripped_section| stripped_section.codes_blocks()) {
+			let section = &version
+				.sections(&chunk.data)
+				.context("Stripped section missing from the chunk")?[stripped_section.section_index];
+			let key = section_key(&stripped_section.palette(version, section), &stripped_section.arr)?;
+			repeats.count(key.clone());
+			keys.push(key);
+		}
+	}
+	Ok(keys.iter().map(|key| repeats.occurrence(key)).collect())
+}
+
+/// Reads an archive, which needs the snapshot it was made against if it's a delta archive
+pub fn read_archive<
+	Transformer: IntegerTransformer,
+	LightTransformer: IntegerTransformer,
+	Coder: IntegerCoder,
+	LightCoder: IntegerCoder,
+>(
+	src: &mut impl Read,
+	base: Option<&Base>,
+) -> anyhow::Result<Vec<Chunk>> {
+	let mut magic = [0u8; 4];
+	src.read_exact(&mut magic)?;
+	if &magic != MAGIC {
+		bail!("Not a miniworld archive");
+	}
+	let version = src.read_u8()?;
+	if version != VERSION {
+		bail!("Unsupported archive version {}", version);
+	}
+	let flags = src.read_u8()?;
+
+	let compressor_id = src.read_u8()?;
+	let compressor = ArchiveCompressor::from_header(compressor_id)?;
+
+	let base = match (flags & FLAG_DELTA != 0, base) {
+		(false, None) => None,
+		(false, Some(_)) => bail!("Not a delta archive, so it can't be restored onto a base"),
+		(true, None) => bail!("Delta archives can only be restored onto the archives they were made against"),
+		(true, Some(base)) => {
+			if src.read_u32::<BigEndian>()? != base.checksum() {
+				bail!("Delta archive was made against a different base archive");
+			}
+			Some(base)
+		}
+	};
+
+	let chunk_count = read_varint(src)? as usize;
+	if chunk_count > 1024 {
+		bail!("More chunks than fit in a region");
+	}
+	let mut headers = vec![];
+	for _ in 0..chunk_count {
+		let index = src.read_u16::<BigEndian>()?;
+		if index >= 1024 {
+			bail!("Chunk index {} is outside the region", index);
+		}
+		headers.push((index, src.read_u32::<BigEndian>()?));
+	}
+	let mut unchanged = vec![None; chunk_count];
+	if base.is_some() {
+		for bits in unchanged.chunks_mut(8) {
+			let byte = src.read_u8()?;
+			for (i, unchanged) in bits.iter_mut().enumerate() {
+				if byte & 1 << i != 0 {
+					*unchanged = Some([0, 0]);
+				}
+			}
+		}
+		for tick_changes in unchanged.iter_mut().flatten() {
+			for change in tick_changes {
+				*change = read_signed_varint(src)?;
+			}
+		}
+	}
+	let changed_count = unchanged.iter().filter(|unchanged| unchanged.is_none()).count();
+
+	// The palettes, coded biomes and light of every chunk follow the NBT columns and the block state dictionary
+	let mut payload = Cursor::new(read_payload(src, &compressor)?);
+	let columns = read_bytes(&mut payload)?;
+	let mut nbt = tree::decode_columns(&columns, changed_count)
+		.context("Failed to read the chunks' NBT")?
+		.into_iter();
+	let entry_count = read_varint(&mut payload)? as usize;
+	let columns = read_bytes(&mut payload)?;
+	let dictionary = BlockStateDictionary::from_entries(
+		tree::decode_columns(&columns, entry_count).context("Failed to read the block state dictionary")?,
+	);
+	let priors_bytes = read_bytes(&mut payload)?;
+	let priors = if priors_bytes.is_empty() {
+		None
+	} else {
+		Some(Priors::read(&mut Cursor::new(priors_bytes)).context("Invalid priors in archive")?)
+	};
+	let mut occurrences = vec![];
+	for _ in 0..read_varint(&mut payload)? {
+		occurrences.push(Occurrence::read(&mut payload)?);
+	}
+	let mut occurrences = occurrences.into_iter();
+
+	let mut chunks = vec![];
+	let mut history = SectionHistory::new();
+	// The legacy palette (if any) and blocks of sections that later ones refer to
+	let mut kept: Vec<(Vec<u16>, [u32; 4096])> = vec![];
+	let mut kept_biomes: Vec<[u32; 64]> = vec![];
+	let mut kept_light: Vec<Vec<i8>> = vec![];
+	for ((index, timestamp), unchanged) in headers.into_iter().zip(unchanged) {
+		if let (Some(tick_changes), Some(base)) = (unchanged, base) {
+			let data = base.unchanged_chunk(index, tick_changes)?;
+			chunks.push(Chunk { index, timestamp, data });
+			continue;
+		}
+		let mut data = nbt.next().context("Chunk NBT missing")?;
+		let version = ChunkVersion::of(&data);
+		// Heightmaps and light emptied by strip_derived are recomputed rather than read
+		let empty_blocks = read_positions(&mut payload)?;
+		let empty_biomes = read_positions(&mut payload)?;
+		let mut derived = StrippedDerived::default();
+		let mut light_below = LightBelow::default();
+		if flags & FLAG_STRIPPED_DERIVED != 0 {
+			derived.heightmaps = read_positions(&mut payload)?;
+		}
+		for (section_index, section) in version.sections_mut(&mut data).into_iter().flatten().enumerate() {
+			let y = section_y(section);
+			if let Some(BlockStatesMut { palette, .. }) = version.block_states_mut(section) {
+				if palette.is_empty() {
+					for _ in 0..read_varint(&mut payload)? {
+						let index = read_varint(&mut payload)? as u32;
+						palette.push(
+							dictionary
+								.get(index)
+								.context("Palette entry missing from the block state dictionary")?
+								.clone(),
+						);
+					}
+				}
+			}
+			if let Some(BlockStatesMut {
+				palette,
+				data: Some(data),
+			}) = version.block_states_mut(section)
+			{
+				if data.is_empty() && !empty_blocks.contains(&section_index) {
+					let palette_length = palette.len() as u32;
+					let occurrence = if palette_length > 1 {
+						occurrences.next().context("Section occurrence missing")?
+					} else {
+						Occurrence::Unique
+					};
+					let arr = match occurrence {
+						Occurrence::Reference(kept_index) => {
+							kept.get(kept_index as usize)
+								.context("Reference to a section that wasn't kept")?
+								.1
+						}
+						_ => {
+							let mut context = history.context(index, y, palette);
+							context.prior_counts = priors.as_ref().map(|priors| priors.initial_counts(palette));
+							let changes_context = base.and_then(|base| base.changes_context(&context, index, y));
+							read_section_blocks::<Transformer, Coder>(
+								src,
+								&compressor,
+								palette_length,
+								&context,
+								changes_context.as_ref(),
+							)?
+						}
+					};
+					if occurrence == Occurrence::Kept {
+						kept.push((vec![], arr));
+					}
+					*data = pack_integers(&arr, palette_bits(palette_length), version.packing());
+				}
+			} else if let Some(legacy_blocks) = version.legacy_blocks_mut(section) {
+				if legacy_blocks.blocks.is_empty() && legacy_blocks.data.is_empty() && !empty_blocks.contains(&section_index) {
+					let occurrence = occurrences.next().context("Section occurrence missing")?;
+					let (palette, arr) = match occurrence {
+						Occurrence::Reference(kept_index) => kept
+							.get(kept_index as usize)
+							.context("Reference to a section that wasn't kept")?
+							.clone(),
+						_ => {
+							let palette_length = read_varint(src)? as u32;
+							let mut palette = vec![];
+							for _ in 0..palette_length {
+								palette.push(read_varint(src)? as u16);
+							}
+							let palette_entries = legacy_palette_entries(&palette);
+							let mut context = history.context(index, y, &palette_entries);
+							context.prior_counts = priors.as_ref().map(|priors| priors.initial_counts(&palette_entries));
+							let changes_context = base.and_then(|base| base.changes_context(&context, index, y));
+							let arr = read_section_blocks::<Transformer, Coder>(
+								src,
+								&compressor,
+								palette_length,
+								&context,
+								changes_context.as_ref(),
+							)?;
+							(palette, arr)
+						}
+					};
+					if occurrence == Occurrence::Kept {
+						kept.push((palette.clone(), arr));
+					}
+					let (blocks, data, add) = legacy_from_palette(&palette, &arr, legacy_blocks.add.is_some());
+					*legacy_blocks.blocks = blocks;
+					*legacy_blocks.data = data;
+					if let (Some(dest), Some(add)) = (legacy_blocks.add, add) {
+						*dest = add;
+					}
+				}
+			}
+			if let Some(BlockStatesMut {
+				palette,
+				data: Some(data),
+			}) = version.biomes_mut(section)
+			{
+				if data.is_empty() && !empty_biomes.contains(&section_index) {
+					let palette_length = palette.len() as u32;
+					let occurrence = Occurrence::read(&mut payload)?;
+					let arr = match occurrence {
+						Occurrence::Reference(kept_index) => *kept_biomes
+							.get(kept_index as usize)
+							.context("Reference to biomes that weren't kept")?,
+						_ => {
+							let mut arr = [0u32; 64];
+							read_array::<Transformer, Coder>(
+								&mut payload,
+								&mut arr,
+								Dimensions::SECTION_BIOMES,
+								palette_length,
+								&SectionContext::default(),
+							)?;
+							arr
+						}
+					};
+					if occurrence == Occurrence::Kept {
+						kept_biomes.push(arr);
+					}
+					*data = pack_integers(&arr, biome_bits(palette_length), version.packing());
+				}
+			}
+			if let Value::Compound(section) = &mut *section {
+				for key in &LIGHT_KEYS {
+					if let Some(Value::ByteArray(light)) = section.get_mut(*key) {
+						if light.is_empty() {
+							let mut mode = payload.read_u8()?;
+							if mode == LIGHT_DERIVED {
+								derived.light.push((section_index, *key));
+								continue;
+							}
+							let context = light_below.context(key, y);
+							let mut occurrence = Occurrence::Unique;
+							if mode == LIGHT_REPEATED {
+								occurrence = Occurrence::read(&mut payload)?;
+								if let Occurrence::Reference(kept_index) = occurrence {
+									*light = kept_light
+										.get(kept_index as usize)
+										.context("Reference to light that wasn't kept")?
+										.clone();
+									light_below.insert(key, y, &unpack_nibbles(light));
+									continue;
+								}
+								mode = payload.read_u8()?;
+							}
+							match mode {
+								LIGHT_CODED => {
+									let mut arr = [0u32; 4096];
+									read_array::<LightTransformer, LightCoder>(
+										&mut payload,
+										&mut arr,
+										Dimensions::SECTION,
+										LIGHT_LEVELS,
+										&context,
+									)?;
+									*light = pack_nibbles(&arr);
+								}
+								LIGHT_PACKED => {
+									let mut packed = vec![0u8; 2048];
+									payload.read_exact(&mut packed)?;
+									*light = packed.into_iter().map(|b| b as i8).collect();
+								}
+								LIGHT_EMPTY => {}
+								level if level < LIGHT_CODED => *light = pack_nibbles(&[level as u32; 4096]),
+								mode => bail!("Unknown light mode {}", mode),
+							}
+							if !light.is_empty() {
+								light_below.insert(key, y, &unpack_nibbles(light));
+							}
+							if occurrence == Occurrence::Kept {
+								kept_light.push(light.clone());
+							}
+						}
+					}
+				}
+			}
+			if let Some((y, palette, arr)) = section_blocks(version, section) {
+				history.insert(index, y, palette, arr);
+			}
+		}
+
+		if flags & FLAG_STRIPPED_DERIVED != 0 {
+			derived::restore_derived(&mut data, &derived);
+		}
+		chunks.push(Chunk { index, timestamp, data });
+	}
+
+	Ok(chunks)
+}
+
+fn write_section_blocks<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	dest: &mut impl Write,
+	compressor: &impl ByteCompressor,
+	mut arr: [u32; 4096],
+	palette_length: u32,
+	context: &SectionContext,
+	changes_context: Option<&SectionContext>,
+) -> anyhow::Result<()> {
+	// Sections with a single palette entry are implicitly all zeroes
+	if palette_length <= 1 {
+		return Ok(());
+	}
+	let mut encoded = vec![];
+	let mut palette_size_transformed =
+		encode_values::<Transformer, Coder>(&mut arr.clone(), Dimensions::SECTION, palette_length, context, &mut encoded)?;
+	// Sections that are in the base snapshot are coded as their changes from it instead, when that's smaller
+	if let Some((changes_context, previous)) = changes_context.and_then(|context| Some((context, context.previous.as_ref()?))) {
+		changes_from(&mut arr, previous);
+		let mut encoded_changes = vec![];
+		let changes_size_transformed = encode_values::<Transformer, Coder>(
+			&mut arr,
+			Dimensions::SECTION,
+			palette_length + 1,
+			changes_context,
+			&mut encoded_changes,
+		)?;
+		let coded_as_changes = encoded_changes.len() < encoded.len();
+		dest.write_u8(coded_as_changes as u8)?;
+		if coded_as_changes {
+			encoded = encoded_changes;
+			palette_size_transformed = changes_size_transformed;
+		}
+	}
+	write_varint(dest, palette_size_transformed as u64)?;
+	write_payload(dest, compressor, &encoded)
+}
+
+fn read_section_blocks<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	src: &mut impl Read,
+	compressor: &impl ByteCompressor,
+	palette_length: u32,
+	context: &SectionContext,
+	changes_context: Option<&SectionContext>,
+) -> anyhow::Result<[u32; 4096]> {
+	let mut arr = [0u32; 4096];
+	if palette_length > 1 {
+		let coded_as_changes = match changes_context.and_then(|context| Some((context, context.previous.as_ref()?))) {
+			Some(changes) if src.read_u8()? != 0 => Some(changes),
+			_ => None,
+		};
+		let palette_size_transformed = read_varint(src)? as u32;
+		let encoded = read_payload(src, compressor)?;
+		match coded_as_changes {
+			Some((changes_context, previous)) => {
+				decode_values::<Transformer, Coder>(
+					&encoded,
+					&mut arr,
+					Dimensions::SECTION,
+					palette_length + 1,
+					palette_size_transformed,
+					changes_context,
+				)?;
+				apply_changes(&mut arr, previous);
+			}
+			None => decode_values::<Transformer, Coder>(
+				&encoded,
+				&mut arr,
+				Dimensions::SECTION,
+				palette_length,
+				palette_size_transformed,
+				context,
+			)?,
+		}
+	}
+	Ok(arr)
+}
+
+/// The NBT of every chunk and the block state dictionary as columns, followed by each chunk's palettes, coded biomes and light,
+/// which are compressed together
+fn region_payload<
+	Transformer: IntegerTransformer,
+	LightTransformer: IntegerTransformer,
+	Coder: IntegerCoder,
+	LightCoder: IntegerCoder,
+>(
+	nbt: &[Value],
+	dictionary: &BlockStateDictionary,
+	priors: &[u8],
+	occurrences: &[Occurrence],
+	stripped_arrays: Vec<Vec<StrippedArray>>,
+) -> anyhow::Result<Vec<u8>> {
+	let mut payload = vec![];
+	let columns = tree::encode_columns(nbt)?;
+	write_varint(&mut payload, columns.len() as u64)?;
+	payload.extend_from_slice(&columns);
+	let columns = tree::encode_columns(dictionary.entries())?;
+	write_varint(&mut payload, dictionary.entries().len() as u64)?;
+	write_varint(&mut payload, columns.len() as u64)?;
+	payload.extend_from_slice(&columns);
+	// Right after the dictionary, as they mostly repeat its block names
+	write_varint(&mut payload, priors.len() as u64)?;
+	payload.extend_from_slice(priors);
+	write_varint(&mut payload, occurrences.len() as u64)?;
+	for occurrence in occurrences {
+		occurrence.write(&mut payload)?;
+	}
+	// Identical biomes and light are only coded once, like sections' blocks
+	let mut biome_repeats = Repeats::new();
+	let mut light_repeats = Repeats::new();
+	for array in stripped_arrays.iter().flatten() {
+		match array {
+			StrippedArray::Biomes { arr, .. } => {
+				biome_repeats.count(array_key(arr)?);
+			}
+			StrippedArray::Light { arr, .. } if !is_uniform(arr) => {
+				light_repeats.count(array_key(arr)?);
+			}
+			_ => {}
+		}
+	}
+	for arrays in stripped_arrays {
+		write_arrays::<Transformer, LightTransformer, Coder, LightCoder>(
+			&mut payload,
+			arrays,
+			&mut biome_repeats,
+			&mut light_repeats,
+		)?;
+	}
+	Ok(payload)
+}
+
+fn write_arrays<
+	Transformer: IntegerTransformer,
+	LightTransformer: IntegerTransformer,
+	Coder: IntegerCoder,
+	LightCoder: IntegerCoder,
+>(
+	payload: &mut Vec<u8>,
+	stripped_arrays: Vec<StrippedArray>,
+	biome_repeats: &mut Repeats,
+	light_repeats: &mut Repeats,
+) -> anyhow::Result<()> {
+	let mut light_below = LightBelow::default();
+	for array in stripped_arrays {
+		match array {
+			StrippedArray::Palette(indices) => {
+				write_varint(payload, indices.len() as u64)?;
+				for index in indices {
+					write_varint(payload, index as u64)?;
+				}
+			}
+			StrippedArray::Biomes { palette_length, mut arr } => {
+				let occurrence = biome_repeats.occurrence(&array_key(&arr)?);
+				occurrence.write(payload)?;
+				if !matches!(occurrence, Occurrence::Reference(_)) {
+					write_array::<Transformer, Coder>(
+						payload,
+						&mut arr,
+						Dimensions::SECTION_BIOMES,
+						palette_length,
+						&SectionContext::default(),
+					)?
+				}
+			}
+			StrippedArray::DerivedHeightmaps(positions) => write_positions(payload, &positions)?,
+			StrippedArray::Empty { blocks, biomes } => {
+				write_positions(payload, &blocks)?;
+				write_positions(payload, &biomes)?;
+			}
+			StrippedArray::DerivedLight => payload.push(LIGHT_DERIVED),
+			StrippedArray::Light { arr, .. } if arr.is_empty() => payload.push(LIGHT_EMPTY),
+			StrippedArray::Light { key, y, mut arr } => {
+				let context = light_below.context(key, y);
+				light_below.insert(key, y, &arr);
+				// Most sections are entirely dark or entirely lit, so those are just their light level
+				if is_uniform(&arr) {
+					payload.push(arr[0] as u8);
+					continue;
+				}
+				let occurrence = light_repeats.occurrence(&array_key(&arr)?);
+				if occurrence != Occurrence::Unique {
+					payload.push(LIGHT_REPEATED);
+					occurrence.write(payload)?;
+					if let Occurrence::Reference(_) = occurrence {
+						continue;
+					}
+				}
+				// Noisy light can code to more than its packed size
+				let packed = pack_nibbles(&arr);
+				let mut coded = vec![];
+				write_array::<LightTransformer, LightCoder>(&mut coded, &mut arr, Dimensions::SECTION, LIGHT_LEVELS, &context)?;
+				if coded.len() < packed.len() {
+					payload.push(LIGHT_CODED);
+					payload.extend_from_slice(&coded);
+				} else {
+					payload.push(LIGHT_PACKED);
+					payload.extend(packed.iter().map(|b| *b as u8));
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+fn write_positions(payload: &mut Vec<u8>, positions: &[usize]) -> anyhow::Result<()> {
+	write_varint(payload, positions.len() as u64)?;
+	for position in positions {
+		write_varint(payload, *position as u64)?;
+	}
+	Ok(())
+}
+
+fn read_positions(payload: &mut Cursor<Vec<u8>>) -> anyhow::Result<Vec<usize>> {
+	(0..read_varint(payload)?)
+		.map(|_| Ok(read_varint(payload)? as usize))
+		.collect()
+}
+
+/// Whether a light array is a single level, which is written as just that level (or is empty)
+fn is_uniform(arr: &[u32]) -> bool {
+	arr.iter().all(|level| *level == arr[0])
+}
+
+/// The indices of a coded biome or light array, which identify arrays that can be written as references to an identical one
+fn array_key(arr: &[u32]) -> anyhow::Result<Vec<u8>> {
+	let mut key = vec![];
+	for &index in arr {
+		write_varint(&mut key, index as u64)?;
+	}
+	Ok(key)
+}
+
+/// Biomes are only stripped with more than one palette entry, and light always has 16 levels, so there are always coded indices
+fn write_array<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	dest: &mut Vec<u8>,
+	arr: &mut [u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	context: &SectionContext,
+) -> anyhow::Result<()> {
+	let mut encoded = vec![];
+	let palette_size_transformed = encode_values::<Transformer, Coder>(arr, dimensions, palette_length, context, &mut encoded)?;
+	write_varint(dest, palette_size_transformed as u64)?;
+	write_varint(dest, encoded.len() as u64)?;
+	dest.extend_from_slice(&encoded);
+	Ok(())
+}
+
+fn read_array<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	src: &mut Cursor<Vec<u8>>,
+	arr: &mut [u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	context: &SectionContext,
+) -> anyhow::Result<()> {
+	let palette_size_transformed = read_varint(src)? as u32;
+	let encoded = read_bytes(src)?;
+	decode_values::<Transformer, Coder>(&encoded, arr, dimensions, palette_length, palette_size_transformed, context)
+}
+
+/// The light levels of the last section coded in a chunk, for each light key, as context for the section above it.
+/// Sections are coded from the bottom up, so light uses the section below rather than the one above that sky light comes from.
+#[derive(Default)]
+struct LightBelow(HashMap<&'static str, (i8, Vec<u32>)>);
+
+impl LightBelow {
+	fn context(&self, key: &str, y: Option<i8>) -> SectionContext {
+		let below = match (y, self.0.get(key)) {
+			(Some(y), Some((below_y, arr))) if y.checked_sub(1) == Some(*below_y) => Some(arr.clone()),
+			_ => None,
+		};
+		SectionContext {
+			below,
+			..SectionContext::default()
+		}
+	}
+
+	fn insert(&mut self, key: &'static str, y: Option<i8>, arr: &[u32]) {
+		if let Some(y) = y {
+			self.0.insert(key, (y, arr.to_vec()));
+		}
+	}
+}
+
+/// Transforms and encodes an array of palette indices, returning the transformed palette size needed to decode it.
+/// The transformer's side information is written ahead of the coded values.
+/// The context is passed to the coder as is, so it only lines up with transformers that keep values in place.
+/// With priors in the context the palette is first renumbered by them (see SectionContext::ranked_by_priors).
+pub fn encode_values<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	arr: &mut [u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	context: &SectionContext,
+	dest: &mut Vec<u8>,
+) -> anyhow::Result<u32> {
+	// Arrays coded against something other than the palette, like a section's changes, aren't renumbered
+	let ranked = context
+		.ranked_by_priors()
+		.filter(|(order, _)| order.len() as u32 == palette_length);
+	let context = match &ranked {
+		Some((order, ranked_context)) => {
+			let mut new_index = vec![0u32; order.len()];
+			for (i, v) in order.iter().enumerate() {
+				new_index[*v as usize] = i as u32;
+			}
+			for v in arr.iter_mut() {
+				*v = new_index[*v as usize];
+			}
+			ranked_context
+		}
+		None => context,
+	};
+	let mut palette_size_transformed = palette_length;
+	let mut side_info = vec![];
+	Transformer::transform(arr, dimensions, &mut palette_size_transformed, &mut side_info);
+	write_varint(dest, side_info.len() as u64)?;
+	dest.extend_from_slice(&side_info);
+	Coder::encode_with_context(arr, dimensions, context, dest, palette_size_transformed);
+	Ok(palette_size_transformed)
+}
+
+/// Decodes values written by encode_values,
+/// given the palette length they were encoded with as well as the transformed palette size
+pub fn decode_values<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	data: &[u8],
+	arr: &mut [u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	mut palette_size_transformed: u32,
+	context: &SectionContext,
+) -> anyhow::Result<()> {
+	let mut reader = Cursor::new(data);
+	let mut side_info = read_bytes(&mut reader)?;
+	if palette_size_transformed == 0 {
+		bail!("Coded values with an empty palette");
+	}
+	// Renumbered under the same condition as in encode_values, as transformers like DeltaLeft change the palette size
+	let ranked = context
+		.ranked_by_priors()
+		.filter(|(order, _)| order.len() as u32 == palette_length);
+	let coder_context = ranked.as_ref().map_or(context, |(_, ranked_context)| ranked_context);
+	Coder::decode_with_context(
+		&data[reader.position() as usize..],
+		coder_context,
+		arr,
+		dimensions,
+		palette_size_transformed,
+	)
+	.context("Failed to decode values")?;
+	// Transformers index by the values, so values outside the palette would be out of bounds
+	if arr.iter().any(|value| *value >= palette_size_transformed) {
+		bail!("Decoded value outside the palette");
+	}
+	Transformer::reverse(arr, dimensions, &mut palette_size_transformed, &mut side_info)
+		.context("Failed to reverse the transform")?;
+	if let Some((order, _)) = ranked {
+		for v in arr.iter_mut() {
+			*v = *order.get(*v as usize).context("Reversed value outside the palette")?;
+		}
+	}
+	Ok(())
+}
+
+/// Unpacks block states, returning None if they can't be reproduced exactly by pack_integers
+pub fn unpack_block_states(data: &[i64], palette_length: u32, packing: Packing) -> Option<[u32; 4096]> {
+	unpack(data, palette_length, palette_bits(palette_length), packing)
+}
+
+/// Unpacks 1.18+ biomes, returning None if they can't be reproduced exactly by pack_integers
+pub fn unpack_biomes(data: &[i64], palette_length: u32, packing: Packing) -> Option<[u32; 64]> {
+	// A single palette entry takes no bits, so there is nothing to unpack
+	if palette_length <= 1 {
+		return None;
+	}
+	unpack(data, palette_length, biome_bits(palette_length), packing)
+}
+
+fn unpack<const N: usize>(data: &[i64], palette_length: u32, num_bits: u8, packing: Packing) -> Option<[u32; N]> {
+	let decoded_data: ArrayVec<u32, N> = PackedIntegerArrayIter::new(data.iter(), num_bits, packing).take(N).collect();
+	let arr = decoded_data.into_inner().ok()?;
+	if arr.iter().any(|value| *value >= palette_length) || pack_integers(&arr, num_bits, packing) != data {
+		return None;
+	}
+	Some(arr)
+}
+
+/// A section whose blocks were replaced with an empty placeholder
+struct StrippedSection {
+	/// Position in the chunk's section list
+	section_index: usize,
+	palette_length: u32,
+	/// The local palette of a pre-flattening section, which isn't stored in the NBT
+	legacy_palette: Option<Vec<u16>>,
+	arr: [u32; 4096],
+}
+
+impl StrippedSection {
+	/// Whether anything is written for the section's blocks, which is all that can be written as a reference
+	fn codes_blocks(&self) -> bool {
+		self.legacy_palette.is_some() || self.palette_length > 1
+	}
+
+	/// The section's palette, built the same way as in read_archive, which only has the palette and height before decoding
+	fn palette(&self, version: ChunkVersion, section: &Value) -> Vec<Value> {
+		match &self.legacy_palette {
+			Some(palette) => legacy_palette_entries(palette),
+			None => version
+				.block_states(section)
+				.map_or_else(Vec::new, |block_states| block_states.palette.clone()),
+		}
+	}
+}
+
+/// A section's palette and blocks, which identify sections that can be written as references to an identical one.
+/// Pre-flattening palettes are block ids rather than block states, so they can't match a flattened section's.
+pub fn section_key(palette: &[Value], arr: &[u32]) -> anyhow::Result<Vec<u8>> {
+	let mut key = vec![];
+	write_varint(&mut key, palette.len() as u64)?;
+	for entry in palette {
+		key.push(entry.id());
+		entry.to_writer(&mut key)?;
+	}
+	for &index in arr {
+		write_varint(&mut key, index as u64)?;
+	}
+	Ok(key)
+}
+
+/// A section's block state palette, 1.18+ biomes or light levels,
+/// which were replaced with an empty placeholder and are coded after the chunk's NBT.
+/// Palettes and light arrays that were already empty are kept as empty ones.
+#[derive(Clone)]
+enum StrippedArray {
+	/// Indices into the block state dictionary
+	Palette(Vec<u32>),
+	Biomes {
+		palette_length: u32,
+		arr: Vec<u32>,
+	},
+	/// A section's BlockLight or SkyLight levels, or an empty array if it was already empty
+	Light {
+		key: &'static str,
+		y: Option<i8>,
+		arr: Vec<u32>,
+	},
+	/// Light emptied by strip_derived
+	DerivedLight,
+	/// The positions of the heightmaps emptied by strip_derived
+	DerivedHeightmaps(Vec<usize>),
+	/// The positions of the sections whose block states (or Blocks and Data) or biomes were already empty,
+	/// which would otherwise look like placeholders
+	Empty {
+		blocks: Vec<usize>,
+		biomes: Vec<usize>,
+	},
+}
+
+/// Empties the block state palettes of every section,
+/// and the blocks, biomes and light of every section that can be reproduced exactly,
+/// returning the remaining NBT, the sections' blocks and the sections' palettes, biomes and light in the order they are coded.
+/// Palette entries are added to the dictionary.
+/// With derived, the chunk has been through strip_derived and the arrays it emptied are recorded to be recomputed.
+fn strip_chunk(
+	chunk_data: &Value,
+	derived: Option<&StrippedDerived>,
+	dictionary: &mut BlockStateDictionary,
+) -> anyhow::Result<(Value, Vec<StrippedSection>, Vec<StrippedArray>)> {
+	let mut data = chunk_data.clone();
+	let version = ChunkVersion::of(&data);
+	let mut stripped = vec![];
+	let mut stripped_arrays = vec![];
+	if let Some(derived) = derived {
+		stripped_arrays.push(StrippedArray::DerivedHeightmaps(derived.heightmaps.clone()));
+	}
+	let (mut empty_blocks, mut empty_biomes) = (vec![], vec![]);
+	for (section_index, section) in version.sections_mut(&mut data).into_iter().flatten().enumerate() {
+		// The palette is emptied after the block states, which need its length
+		if let Some(block_states) = version.block_states(section) {
+			let indices = block_states
+				.palette
+				.iter()
+				.map(|entry| dictionary.intern(entry))
+				.collect::<anyhow::Result<_>>()?;
+			stripped_arrays.push(StrippedArray::Palette(indices));
+		}
+		if let Some((palette_length, arr)) = version
+			.biomes_mut(section)
+			.and_then(|biomes| strip_biomes(biomes, version.packing()))
+		{
+			stripped_arrays.push(StrippedArray::Biomes {
+				palette_length,
+				arr: arr.to_vec(),
+			});
+		} else if version
+			.biomes(section)
+			.is_some_and(|biomes| biomes.data.is_some_and(Vec::is_empty))
+		{
+			empty_biomes.push(section_index);
+		}
+		let y = section_y(section);
+		if let Value::Compound(section) = &mut *section {
+			for key in &LIGHT_KEYS {
+				if let Some(Value::ByteArray(light)) = section.get_mut(*key) {
+					if light.len() == 2048 {
+						stripped_arrays.push(StrippedArray::Light {
+							key,
+							y,
+							arr: unpack_nibbles(light).to_vec(),
+						});
+						light.clear();
+					} else if derived.is_some_and(|derived| derived.light.contains(&(section_index, *key))) {
+						stripped_arrays.push(StrippedArray::DerivedLight);
+					} else if light.is_empty() {
+						stripped_arrays.push(StrippedArray::Light { key, y, arr: vec![] });
+					}
+				}
+			}
+		}
+		if let Some((palette_length, arr)) = version
+			.block_states_mut(section)
+			.and_then(|block_states| strip_block_states(block_states, version.packing()))
+		{
+			stripped.push(StrippedSection {
+				section_index,
+				palette_length,
+				legacy_palette: None,
+				arr,
+			});
+		} else if let Some((palette, arr)) = version.legacy_blocks_mut(section).and_then(strip_legacy_blocks) {
+			stripped.push(StrippedSection {
+				section_index,
+				palette_length: palette.len() as u32,
+				legacy_palette: Some(palette),
+				arr,
+			});
+		} else if version
+			.block_states(section)
+			.is_some_and(|block_states| block_states.data.is_some_and(Vec::is_empty))
+			|| version
+				.legacy_blocks(section)
+				.is_some_and(|legacy_blocks| legacy_blocks.blocks.is_empty() && legacy_blocks.data.is_empty())
+		{
+			empty_blocks.push(section_index);
+		}
+		if let Some(block_states) = version.block_states_mut(section) {
+			block_states.palette.clear();
+		}
+	}
+	stripped_arrays.insert(
+		0,
+		StrippedArray::Empty {
+			blocks: empty_blocks,
+			biomes: empty_biomes,
+		},
+	);
+	Ok((data, stripped, stripped_arrays))
+}
+
+/// Replaces a section's block states with an empty placeholder, returning the palette length and unpacked values
+fn strip_block_states(block_states: BlockStatesMut, packing: Packing) -> Option<(u32, [u32; 4096])> {
+	let palette_length = block_states.palette.len() as u32;
+	let data = block_states.data?;
+	if data.is_empty() {
+		return None;
+	}
+	let arr = unpack_block_states(data, palette_length, packing)?;
+	data.clear();
+	Some((palette_length, arr))
+}
+
+/// Replaces a section's biomes with an empty placeholder, returning the palette length and unpacked values
+fn strip_biomes(biomes: BlockStatesMut, packing: Packing) -> Option<(u32, [u32; 64])> {
+	let palette_length = biomes.palette.len() as u32;
+	let data = biomes.data?;
+	let arr = unpack_biomes(data, palette_length, packing)?;
+	data.clear();
+	Some((palette_length, arr))
+}
+
+/// Empties a pre-flattening section's block arrays, returning its local palette and indices
+fn strip_legacy_blocks(legacy_blocks: LegacyBlocksMut) -> Option<(Vec<u16>, [u32; 4096])> {
+	let (palette, arr) = legacy_to_palette(
+		legacy_blocks.blocks,
+		legacy_blocks.data,
+		legacy_blocks.add.as_deref().map(Vec::as_slice),
+	)?;
+	legacy_blocks.blocks.clear();
+	legacy_blocks.data.clear();
+	if let Some(add) = legacy_blocks.add {
+		add.clear();
+	}
+	Some((palette, arr))
+}
+
+fn write_payload(dest: &mut impl Write, compressor: &impl ByteCompressor, data: &[u8]) -> anyhow::Result<()> {
+	let mut compressed = vec![];
+	compressor.compress(data, &mut compressed);
+	write_varint(dest, compressed.len() as u64)?;
+	dest.write_all(&compressed)?;
+	Ok(())
+}
+
+fn read_payload(src: &mut impl Read, compressor: &impl ByteCompressor) -> anyhow::Result<Vec<u8>> {
+	let compressed = read_bytes(src)?;
+	let mut data = vec![];
+	compressor
+		.decompress(&compressed, &mut data)
+		.context("Failed to decompress")?;
+	Ok(data)
+}
+
+/// Reads bytes written after their length, only allocating as many as are actually there
+fn read_bytes(src: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+	let length = read_varint(src)?;
+	let mut bytes = vec![];
+	src.by_ref().take(length).read_to_end(&mut bytes)?;
+	if bytes.len() as u64 != length {
+		bail!("Unexpected end of archive");
+	}
+	Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use nbt::Map;
+
+	use super::*;
+	use crate::chunk::set_nibble;
+	use crate::derived::restore_derived;
+	use crate::integercoders::NeighbourContextArithmeticCoding;
+	use crate::integertransformers;
+
+	fn compound(entries: Vec<(&str, Value)>) -> Value {
+		Value::Compound(
+			entries
+				.into_iter()
+				.map(|(key, value)| (key.to_string(), value))
+				.collect::<Map<String, Value>>(),
+		)
+	}
+
+	fn block(name: &str) -> Value {
+		compound(vec![("Name", Value::String(format!("minecraft:{}", name)))])
+	}
+
+	fn paletted(palette: Vec<Value>, arr: &[u32], num_bits: u8) -> Value {
+		let mut entries = vec![("palette", Value::List(palette))];
+		if num_bits > 0 {
+			entries.push(("data", Value::LongArray(pack_integers(arr, num_bits, Packing::Padded))));
+		}
+		compound(entries)
+	}
+
+	fn nibbles(f: impl Fn(usize) -> u8) -> Value {
+		let mut array = vec![0i8; 2048];
+		for i in 0..4096 {
+			set_nibble(&mut array, i, f(i));
+		}
+		Value::ByteArray(array)
+	}
+
+	/// A 1.18 chunk with stone and dirt under a layer of air, sky light and heightmaps recomputed to match,
+	/// block light around a torch, and a section whose block states and block light were already empty
+	fn chunk_1_18(index: u16, seed: u32) -> Chunk {
+		let terrain = |i: usize| {
+			if i / 256 >= 12 {
+				2
+			} else {
+				(i as u32 * 7 + seed) % 11 / 5 % 2
+			}
+		};
+		let biomes: Vec<u32> = (0..64).map(|i| (i as u32 + seed) / 16 % 2).collect();
+		let section = |y: i8, arr: &[u32]| {
+			compound(vec![
+				("Y", Value::Byte(y)),
+				(
+					"block_states",
+					paletted(vec![block("stone"), block("dirt"), block("air")], arr, palette_bits(3)),
+				),
+				(
+					"biomes",
+					paletted(
+						vec![
+							Value::String("minecraft:plains".into()),
+							Value::String("minecraft:forest".into()),
+						],
+						&biomes,
+						1,
+					),
+				),
+				("BlockLight", nibbles(|i| (i / 256 % 16) as u8)),
+				("SkyLight", Value::ByteArray(vec![])),
+			])
+		};
+		let arr: Vec<u32> = (0..4096).map(terrain).collect();
+		let mut below = arr.clone();
+		below[seed as usize] = 0;
+		let mut data = compound(vec![
+			("DataVersion", Value::Int(2975)),
+			("xPos", Value::Int(index as i32 % 32)),
+			("zPos", Value::Int(index as i32 / 32)),
+			("yPos", Value::Int(-1)),
+			("Status", Value::String("full".into())),
+			("LastUpdate", Value::Long(1000 + seed as i64)),
+			("InhabitedTime", Value::Long(50)),
+			(
+				"Heightmaps",
+				compound(vec![
+					("WORLD_SURFACE", Value::LongArray(vec![])),
+					("MOTION_BLOCKING", Value::LongArray(vec![])),
+				]),
+			),
+			(
+				"sections",
+				Value::List(vec![
+					section(-1, &below),
+					section(0, &arr),
+					compound(vec![
+						("Y", Value::Byte(1)),
+						("block_states", paletted(vec![block("air")], &[], 0)),
+						("biomes", paletted(vec![Value::String("minecraft:plains".into())], &[], 0)),
+						("SkyLight", Value::ByteArray(vec![])),
+					]),
+					compound(vec![
+						("Y", Value::Byte(2)),
+						(
+							"block_states",
+							compound(vec![
+								("palette", Value::List(vec![block("stone"), block("air")])),
+								("data", Value::LongArray(vec![])),
+							]),
+						),
+						("BlockLight", Value::ByteArray(vec![])),
+					]),
+				]),
+			),
+		]);
+		restore_derived(
+			&mut data,
+			&StrippedDerived {
+				heightmaps: vec![0, 1],
+				light: vec![(0, "SkyLight"), (1, "SkyLight"), (2, "SkyLight")],
+			},
+		);
+		Chunk {
+			index,
+			timestamp: 1_600_000_000 + seed,
+			data,
+		}
+	}
+
+	/// A 1.12 chunk with numeric block IDs, including some above 255
+	fn chunk_1_12(index: u16, seed: u32) -> Chunk {
+		let blocks: Vec<i8> = (0..4096)
+			.map(|i| if i / 256 >= 8 { 0 } else { ((i as u32 + seed) % 3 + 1) as i8 })
+			.collect();
+		let section = |y: i8, add: bool| {
+			let mut entries = vec![
+				("Y", Value::Byte(y)),
+				("Blocks", Value::ByteArray(blocks.clone())),
+				("Data", nibbles(|i| (i % 3) as u8)),
+				("BlockLight", nibbles(|_| 0)),
+				("SkyLight", nibbles(|i| if i / 256 >= 8 { 15 } else { 0 })),
+			];
+			if add {
+				entries.push(("Add", nibbles(|i| (i % 2) as u8)));
+			}
+			compound(entries)
+		};
+		let level = compound(vec![
+			("xPos", Value::Int(index as i32 % 32)),
+			("zPos", Value::Int(index as i32 / 32)),
+			("LastUpdate", Value::Long(1000 + seed as i64)),
+			("InhabitedTime", Value::Long(50)),
+			("Sections", Value::List(vec![section(0, false), section(1, true)])),
+		]);
+		let data = compound(vec![("DataVersion", Value::Int(1343)), ("Level", level)]);
+		Chunk {
+			index,
+			timestamp: 1_500_000_000 + seed,
+			data,
+		}
+	}
+
+	fn write(chunks: &[Chunk], base: Option<&Base>, strip_derived: bool) -> Vec<u8> {
+		write_with_priors(chunks, base, None, strip_derived)
+	}
+
+	fn write_with_priors(chunks: &[Chunk], base: Option<&Base>, priors: Option<&Priors>, strip_derived: bool) -> Vec<u8> {
+		let mut archive = vec![];
+		let compressor = ArchiveCompressor::new("zlib", None, None).unwrap();
+		write_archive::<
+			integertransformers::None,
+			integertransformers::None,
+			NeighbourContextArithmeticCoding,
+			NeighbourContextArithmeticCoding,
+		>(chunks, base, &mut archive, &compressor, priors, strip_derived)
+		.unwrap();
+		archive
+	}
+
+	fn read(archive: &[u8], base: Option<&Base>) -> anyhow::Result<Vec<Chunk>> {
+		read_archive::<
+			integertransformers::None,
+			integertransformers::None,
+			NeighbourContextArithmeticCoding,
+			NeighbourContextArithmeticCoding,
+		>(&mut Cursor::new(archive), base)
+	}
+
+	fn assert_same(read: &[Chunk], chunks: &[Chunk]) {
+		assert_eq!(read.len(), chunks.len());
+		for (read, chunk) in read.iter().zip(chunks) {
+			assert_eq!((read.index, read.timestamp), (chunk.index, chunk.timestamp));
+			assert!(read.data == chunk.data, "chunk {} differs", chunk.index);
+		}
+	}
+
+	/// The same chunks twice over, so sections, biomes and light repeat across chunks
+	fn region() -> Vec<Chunk> {
+		vec![
+			chunk_1_18(0, 0),
+			chunk_1_18(1, 3),
+			chunk_1_18(2, 0),
+			chunk_1_12(32, 0),
+			chunk_1_12(33, 1),
+			chunk_1_12(34, 0),
+		]
+	}
+
+	#[test]
+	fn archives_round_trip() {
+		let chunks = region();
+		for strip_derived in [false, true] {
+			let archive = write(&chunks, None, strip_derived);
+			assert_same(&read(&archive, None).unwrap(), &chunks);
+		}
+	}
+
+	#[test]
+	fn derived_data_is_stripped() {
+		let mut data = chunk_1_18(0, 0).data;
+		let stripped = derived::strip_derived(&mut data);
+		assert_eq!(stripped.heightmaps, [0, 1]);
+		assert!(stripped.light.contains(&(1, "SkyLight")));
+		assert!(write(&region(), None, true).len() < write(&region(), None, false).len());
+	}
+
+	#[test]
+	fn delta_archives_round_trip() {
+		for strip_derived in [false, true] {
+			let base_archive = write(&region(), None, strip_derived);
+			let base = Base::new(read(&base_archive, None).unwrap(), &base_archive);
+
+			let mut chunks = region();
+			// Only the tick counts change in the first chunk, and a block changes in the second
+			if let Value::Compound(root) = &mut chunks[0].data {
+				root.insert("LastUpdate".into(), Value::Long(5000));
+				root.insert("InhabitedTime".into(), Value::Long(10));
+			}
+			if let Some(Value::Compound(section)) = ChunkVersion::of(&chunks[1].data)
+				.sections_mut(&mut chunks[1].data)
+				.and_then(|sections| sections.get_mut(1))
+			{
+				if let Some(Value::Compound(block_states)) = section.get_mut("block_states") {
+					block_states.insert("data".into(), Value::LongArray(pack_integers(&[2; 4096], 4, Packing::Padded)));
+				}
+			}
+			// A chunk that isn't in the base
+			chunks.push(chunk_1_18(3, 5));
+			assert_eq!(base.unchanged(&chunks[0]), Some([4000, -40]));
+			assert_eq!(base.unchanged(&chunks[1]), None);
+			assert_eq!(base.unchanged(&chunks[2]), Some([0, 0]));
+
+			let archive = write(&chunks, Some(&base), strip_derived);
+			assert_same(&read(&archive, Some(&base)).unwrap(), &chunks);
+			assert!(read(&archive, None).is_err());
+			assert!(archive.len() < write(&chunks, None, strip_derived).len());
+		}
+	}
+
+	#[test]
+	fn delta_archives_need_their_base() {
+		let base_archive = write(&region(), None, false);
+		let base = Base::new(read(&base_archive, None).unwrap(), &base_archive);
+		let archive = write(&region(), Some(&base), false);
+
+		let other_archive = write(&region()[1..], None, false);
+		let other_base = Base::new(read(&other_archive, None).unwrap(), &other_archive);
+		assert!(read(&archive, Some(&other_base)).is_err());
+		assert!(read(&base_archive, Some(&base)).is_err());
+	}
+
+	#[test]
+	fn changes_contexts_hold_the_neighbours_changes() {
+		let base_archive = write(&region(), None, false);
+		let base = Base::new(read(&base_archive, None).unwrap(), &base_archive);
+		let palette = vec![block("stone"), block("dirt"), block("air")];
+		// One block changed in the section below, the section to the west is unchanged
+		// and the chunk to the north isn't in the base
+		let mut below = base.previous_section(1, Some(-1), &palette).unwrap();
+		below[5] = 2;
+		let west = base.previous_section(0, Some(0), &palette);
+		let context = SectionContext {
+			palette,
+			below: Some(below),
+			west,
+			north: Some(vec![2; 4096]),
+			..SectionContext::default()
+		};
+
+		let changes_context = base.changes_context(&context, 1, Some(0)).unwrap();
+		assert_eq!(changes_context.previous, base.previous_section(1, Some(0), &context.palette));
+		let below = changes_context.below.unwrap();
+		assert_eq!((below[5], below.iter().filter(|v| **v != 0).count()), (3, 1));
+		assert!(changes_context.west.unwrap().iter().all(|v| *v == 0));
+		assert!(changes_context.north.unwrap().iter().all(|v| *v == 3));
+		assert!(base.changes_context(&context, 1, Some(5)).is_none());
+	}
+
+	fn ranked_round_trip<Transformer: IntegerTransformer>() {
+		let palette = vec![block("stone"), block("dirt"), block("air"), block("water"), block("sand")];
+		let context = SectionContext {
+			palette,
+			prior_counts: Some(vec![1, 9, 4, 1, 2]),
+			..SectionContext::default()
+		};
+		let arr: Vec<u32> = (0..4096).map(|i| (i as u32 / 7 + i as u32 / 300) % 5).collect();
+		let mut encoded = vec![];
+		let palette_size_transformed = encode_values::<Transformer, NeighbourContextArithmeticCoding>(
+			&mut arr.clone(),
+			Dimensions::SECTION,
+			5,
+			&context,
+			&mut encoded,
+		)
+		.unwrap();
+		let mut decoded = vec![0u32; arr.len()];
+		decode_values::<Transformer, NeighbourContextArithmeticCoding>(
+			&encoded,
+			&mut decoded,
+			Dimensions::SECTION,
+			5,
+			palette_size_transformed,
+			&context,
+		)
+		.unwrap();
+		assert_eq!(decoded, arr);
+	}
+
+	#[test]
+	fn values_ranked_by_priors_round_trip() {
+		ranked_round_trip::<integertransformers::None>();
+		// Both change the palette size
+		ranked_round_trip::<integertransformers::DeltaLeft>();
+		ranked_round_trip::<integertransformers::MoveToFrontLookbehind>();
+	}
+
+	#[test]
+	fn priors_are_only_stored_when_they_make_archives_smaller() {
+		let chunks = region();
+		let mut priors = Priors::new();
+		for chunk in &chunks {
+			let version = ChunkVersion::of(&chunk.data);
+			for (_, palette, arr) in version
+				.sections(&chunk.data)
+				.into_iter()
+				.flatten()
+				.filter_map(|section| section_blocks(version, section))
+			{
+				priors.accumulate(&palette, &arr);
+			}
+		}
+		priors.normalise();
+		let archive = write_with_priors(&chunks, None, Some(&priors), false);
+		assert!(archive.len() <= write(&chunks, None, false).len());
+		assert_same(&read(&archive, None).unwrap(), &chunks);
+	}
+}
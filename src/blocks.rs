@@ -0,0 +1,349 @@
+use nbt::Value;
+
+/// The properties of a block state that heightmaps and light are derived from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockProperties {
+	/// How much light is lost passing through the block: 0 for air and glass, 1 for leaves and water, 15 for full opaque blocks
+	pub opacity: u8,
+	/// The block light level the block gives off
+	pub emission: u8,
+	pub is_air: bool,
+	/// Whether the block stops entities, for the MOTION_BLOCKING and OCEAN_FLOOR heightmaps
+	pub blocks_motion: bool,
+	pub is_leaves: bool,
+	/// Whether the block is or contains water or lava
+	pub has_fluid: bool,
+}
+
+impl BlockProperties {
+	pub const AIR: BlockProperties = BlockProperties {
+		opacity: 0,
+		emission: 0,
+		is_air: true,
+		blocks_motion: false,
+		is_leaves: false,
+		has_fluid: false,
+	};
+	const OPAQUE: BlockProperties = BlockProperties {
+		opacity: 15,
+		emission: 0,
+		is_air: false,
+		blocks_motion: true,
+		is_leaves: false,
+		has_fluid: false,
+	};
+}
+
+// The bundled block property table. Blocks not listed are full opaque blocks, which most blocks are.
+// Names are without the minecraft: namespace; entries starting with _ match names ending with them.
+
+const AIR: &[&str] = &["air", "cave_air", "void_air"];
+
+const FLUIDS: &[&str] = &[
+	"water",
+	"lava",
+	"bubble_column",
+	"seagrass",
+	"tall_seagrass",
+	"kelp",
+	"kelp_plant",
+];
+
+/// Blocks light passes through that entities walk through, like plants and torches
+const NON_SOLID: &[&str] = &[
+	"grass",
+	"tall_grass",
+	"fern",
+	"large_fern",
+	"dead_bush",
+	"dandelion",
+	"poppy",
+	"blue_orchid",
+	"allium",
+	"azure_bluet",
+	"_tulip",
+	"oxeye_daisy",
+	"cornflower",
+	"lily_of_the_valley",
+	"wither_rose",
+	"sunflower",
+	"lilac",
+	"rose_bush",
+	"peony",
+	"_sapling",
+	"brown_mushroom",
+	"red_mushroom",
+	"crimson_fungus",
+	"warped_fungus",
+	"crimson_roots",
+	"warped_roots",
+	"nether_sprouts",
+	"weeping_vines",
+	"weeping_vines_plant",
+	"twisting_vines",
+	"twisting_vines_plant",
+	"cave_vines",
+	"cave_vines_plant",
+	"hanging_roots",
+	"spore_blossom",
+	"glow_lichen",
+	"vine",
+	"sugar_cane",
+	"wheat",
+	"carrots",
+	"potatoes",
+	"beetroots",
+	"melon_stem",
+	"pumpkin_stem",
+	"attached_melon_stem",
+	"attached_pumpkin_stem",
+	"nether_wart",
+	"sweet_berry_bush",
+	"snow",
+	"redstone_wire",
+	"lever",
+	"tripwire",
+	"tripwire_hook",
+	"_button",
+	"rail",
+	"_rail",
+	"torch",
+	"_torch",
+	"fire",
+	"soul_fire",
+	"nether_portal",
+	"end_portal",
+	"end_gateway",
+	"structure_void",
+	"light",
+	"_carpet",
+	"_coral",
+	"_coral_fan",
+	"_coral_wall_fan",
+	"scaffolding",
+];
+
+/// Solid blocks that let light through like air, mostly ones that don't fill their whole block
+const PARTIAL: &[&str] = &[
+	"_slab",
+	"_stairs",
+	"_fence",
+	"_fence_gate",
+	"_wall",
+	"_door",
+	"_trapdoor",
+	"_pane",
+	"glass_pane",
+	"iron_bars",
+	"chain",
+	"_sign",
+	"_banner",
+	"_pressure_plate",
+	"_bed",
+	"candle",
+	"_candle",
+	"_head",
+	"_skull",
+	"ladder",
+	"cactus",
+	"chest",
+	"trapped_chest",
+	"ender_chest",
+	"enchanting_table",
+	"anvil",
+	"chipped_anvil",
+	"damaged_anvil",
+	"brewing_stand",
+	"cauldron",
+	"water_cauldron",
+	"lava_cauldron",
+	"powder_snow_cauldron",
+	"hopper",
+	"lectern",
+	"stonecutter",
+	"grindstone",
+	"bell",
+	"lantern",
+	"soul_lantern",
+	"campfire",
+	"soul_campfire",
+	"end_rod",
+	"lightning_rod",
+	"flower_pot",
+	"sea_pickle",
+	"turtle_egg",
+	"cake",
+	"_cake",
+	"bamboo",
+	"daylight_detector",
+	"repeater",
+	"comparator",
+	"conduit",
+	"dragon_egg",
+	"end_portal_frame",
+	"farmland",
+	"dirt_path",
+	"grass_path",
+	"composter",
+	"lily_pad",
+	"pointed_dripstone",
+	"big_dripleaf",
+	"big_dripleaf_stem",
+	"small_dripleaf",
+	"amethyst_cluster",
+	"_amethyst_bud",
+	"azalea",
+	"flowering_azalea",
+	"honey_block",
+	"cocoa",
+	"_shulker_box",
+	"shulker_box",
+	"piston_head",
+	"moving_piston",
+	"chorus_plant",
+	"chorus_flower",
+	"bamboo_sapling",
+	"glass",
+	"_stained_glass",
+	"barrier",
+];
+
+/// Full blocks that dim light passing through them
+const TRANSLUCENT: &[&str] = &["ice", "frosted_ice", "slime_block", "beacon", "spawner", "cobweb"];
+
+/// Blocks that always give off light
+const EMISSION: &[(&str, u8)] = &[
+	("lava", 15),
+	("fire", 15),
+	("glowstone", 15),
+	("sea_lantern", 15),
+	("shroomlight", 15),
+	("beacon", 15),
+	("jack_o_lantern", 15),
+	("lantern", 15),
+	("end_gateway", 15),
+	("end_portal", 15),
+	("conduit", 15),
+	("ochre_froglight", 15),
+	("verdant_froglight", 15),
+	("pearlescent_froglight", 15),
+	("torch", 14),
+	("wall_torch", 14),
+	("end_rod", 14),
+	("nether_portal", 11),
+	("soul_fire", 10),
+	("soul_torch", 10),
+	("soul_wall_torch", 10),
+	("soul_lantern", 10),
+	("crying_obsidian", 10),
+	("enchanting_table", 7),
+	("ender_chest", 7),
+	("glow_lichen", 7),
+	("amethyst_cluster", 5),
+	("large_amethyst_bud", 4),
+	("magma_block", 3),
+	("medium_amethyst_bud", 2),
+	("small_amethyst_bud", 1),
+	("brewing_stand", 1),
+	("brown_mushroom", 1),
+	("dragon_egg", 1),
+	("end_portal_frame", 1),
+	("sculk_sensor", 1),
+];
+
+/// Blocks that give off light when their lit property is true
+const LIT_EMISSION: &[(&str, u8)] = &[
+	("redstone_lamp", 15),
+	("campfire", 15),
+	("furnace", 13),
+	("blast_furnace", 13),
+	("smoker", 13),
+	("soul_campfire", 10),
+	("redstone_ore", 9),
+	("deepslate_redstone_ore", 9),
+	("redstone_torch", 7),
+	("redstone_wall_torch", 7),
+];
+
+fn matches(names: &[&str], name: &str) -> bool {
+	names.iter().any(|pattern| {
+		if pattern.starts_with('_') {
+			name.ends_with(pattern)
+		} else {
+			name == *pattern
+		}
+	})
+}
+
+/// Looks up a palette entry in the bundled table, using its Name and the Properties that affect light
+pub fn block_properties(entry: &Value) -> BlockProperties {
+	let entry = match entry {
+		Value::Compound(entry) => entry,
+		_ => return BlockProperties::OPAQUE,
+	};
+	let name = match entry.get("Name") {
+		Some(Value::String(name)) => name.strip_prefix("minecraft:").unwrap_or(name),
+		_ => return BlockProperties::OPAQUE,
+	};
+	let property = |key: &str| match entry.get("Properties") {
+		Some(Value::Compound(properties)) => match properties.get(key) {
+			Some(Value::String(value)) => Some(value.as_str()),
+			_ => None,
+		},
+		_ => None,
+	};
+
+	if matches(AIR, name) {
+		return BlockProperties::AIR;
+	}
+	let mut properties = BlockProperties::OPAQUE;
+	if matches(FLUIDS, name) {
+		properties.opacity = 1;
+		properties.blocks_motion = false;
+		properties.has_fluid = true;
+	} else if name.ends_with("_leaves") {
+		properties.opacity = 1;
+		properties.is_leaves = true;
+	} else if matches(NON_SOLID, name) {
+		properties.opacity = 0;
+		properties.blocks_motion = false;
+	} else if matches(PARTIAL, name) {
+		properties.opacity = 0;
+	} else if matches(TRANSLUCENT, name) {
+		properties.opacity = 1;
+	}
+	if property("waterlogged") == Some("true") {
+		properties.opacity = properties.opacity.max(1);
+		properties.has_fluid = true;
+	}
+
+	properties.emission = match EMISSION.iter().find(|(emitter, _)| *emitter == name) {
+		Some((_, emission)) => *emission,
+		None => match LIT_EMISSION.iter().find(|(emitter, _)| *emitter == name) {
+			Some((_, emission)) if property("lit") == Some("true") => *emission,
+			_ => 0,
+		},
+	};
+	// Blocks whose light level depends on how many there are or a level property
+	let count = |key: &str| property(key).and_then(|value| value.parse::<u8>().ok()).unwrap_or(0);
+	if name == "candle" || name.ends_with("_candle") {
+		properties.emission = if property("lit") == Some("true") {
+			3 * count("candles").min(4)
+		} else {
+			0
+		};
+	} else if name == "sea_pickle" {
+		properties.emission = if property("waterlogged") == Some("true") {
+			3 + 3 * count("pickles").min(4)
+		} else {
+			0
+		};
+	} else if name == "light" {
+		properties.emission = count("level").min(15);
+	} else if name == "respawn_anchor" {
+		properties.emission = [0, 3, 7, 11, 15][count("charges").min(4) as usize];
+	} else if (name == "cave_vines" || name == "cave_vines_plant") && property("berries") == Some("true") {
+		properties.emission = 14;
+	}
+	properties
+}
@@ -1,52 +1,244 @@
-use std::io::Cursor;
+use std::{
+	fmt,
+	io::{self, Cursor},
+};
 
-use flate2::{Compression, read::{ZlibDecoder, ZlibEncoder}};
+use anyhow::bail;
+use flate2::{
+	read::{ZlibDecoder, ZlibEncoder},
+	Compression,
+};
 
 pub trait ByteCompressor {
-	fn compress(data: &Vec<u8>, dest: &mut Vec<u8>);
-	fn decompress(data: &Vec<u8>, dest: &mut Vec<u8>);
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>);
+	/// Fails on data that wasn't produced by compress, rather than panicking, as it's read from archives
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()>;
 }
 
+#[derive(Clone)]
 pub struct None;
 
 impl ByteCompressor for None {
-    fn compress(data: &Vec<u8>, dest: &mut Vec<u8>) {
-        dest.extend_from_slice(data)
-    }
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
+		dest.extend_from_slice(data)
+	}
 
-    fn decompress(data: &Vec<u8>, dest: &mut Vec<u8>) {
-        dest.extend_from_slice(data)
-    }
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		dest.extend_from_slice(data);
+		Ok(())
+	}
 }
 
-pub struct LZMA;
+/// LZMA (xz) with a preset level from 0 to 9
+#[derive(Clone)]
+pub struct Lzma {
+	pub level: u32,
+}
+
+impl Default for Lzma {
+	fn default() -> Lzma {
+		Lzma { level: 9 }
+	}
+}
 
-impl ByteCompressor for LZMA {
-    fn compress(data: &Vec<u8>, dest: &mut Vec<u8>) {
+impl ByteCompressor for Lzma {
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
 		let mut cursor = Cursor::new(data);
-        let mut reader = xz2::read::XzEncoder::new(&mut cursor, 9);
+		let mut reader = xz2::read::XzEncoder::new(&mut cursor, self.level);
 		std::io::copy(&mut reader, dest).unwrap();
-    }
+	}
 
-    fn decompress(data: &Vec<u8>, dest: &mut Vec<u8>) {
-        let mut cursor = Cursor::new(data);
-        let mut reader = xz2::read::XzDecoder::new(&mut cursor);
-		std::io::copy(&mut reader, dest).unwrap();
-    }
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		let mut cursor = Cursor::new(data);
+		let mut reader = xz2::read::XzDecoder::new(&mut cursor);
+		std::io::copy(&mut reader, dest)?;
+		Ok(())
+	}
 }
 
-pub struct Zlib;
+/// Zlib with a level from 0 to 9
+#[derive(Clone)]
+pub struct Zlib {
+	pub level: u32,
+}
+
+impl Default for Zlib {
+	fn default() -> Zlib {
+		Zlib { level: 9 }
+	}
+}
 
 impl ByteCompressor for Zlib {
-    fn compress(data: &Vec<u8>, dest: &mut Vec<u8>) {
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
 		let mut cursor = Cursor::new(data);
-        let mut reader = ZlibEncoder::new(&mut cursor, Compression::best());
+		let mut reader = ZlibEncoder::new(&mut cursor, Compression::new(self.level));
 		std::io::copy(&mut reader, dest).unwrap();
-    }
+	}
+
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		let mut cursor = Cursor::new(data);
+		let mut reader = ZlibDecoder::new(&mut cursor);
+		std::io::copy(&mut reader, dest)?;
+		Ok(())
+	}
+}
 
-    fn decompress(data: &Vec<u8>, dest: &mut Vec<u8>) {
-        let mut cursor = Cursor::new(data);
-        let mut reader = ZlibDecoder::new(&mut cursor);
+/// Brotli with a quality from 0 to 11 and a window size of 2^lgwin bytes, from 10 to 24
+#[derive(Clone)]
+pub struct Brotli {
+	pub quality: u32,
+	pub lgwin: u32,
+}
+
+impl Default for Brotli {
+	fn default() -> Brotli {
+		Brotli { quality: 11, lgwin: 22 }
+	}
+}
+
+impl ByteCompressor for Brotli {
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
+		let mut cursor = Cursor::new(data);
+		let mut reader = brotli::CompressorReader::new(&mut cursor, 4096, self.quality, self.lgwin);
 		std::io::copy(&mut reader, dest).unwrap();
-    }
-}
\ No newline at end of file
+	}
+
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		let mut cursor = Cursor::new(data);
+		let mut reader = brotli::Decompressor::new(&mut cursor, 4096);
+		std::io::copy(&mut reader, dest)?;
+		Ok(())
+	}
+}
+
+/// Zstandard with a level from 1 to 22
+#[derive(Clone)]
+pub struct Zstd {
+	pub level: i32,
+}
+
+impl Default for Zstd {
+	fn default() -> Zstd {
+		Zstd { level: 19 }
+	}
+}
+
+impl ByteCompressor for Zstd {
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
+		let mut cursor = Cursor::new(data);
+		let mut reader = zstd::stream::read::Encoder::new(&mut cursor, self.level).unwrap();
+		std::io::copy(&mut reader, dest).unwrap();
+	}
+
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		let mut cursor = Cursor::new(data);
+		let mut reader = zstd::stream::read::Decoder::new(&mut cursor)?;
+		std::io::copy(&mut reader, dest)?;
+		Ok(())
+	}
+}
+
+/// The compressors archives can be written with, picked per run with --compressor, --level and --window.
+/// Archive headers record which one was used, as decompressing doesn't need the level.
+#[derive(Clone)]
+pub enum ArchiveCompressor {
+	None(None),
+	Lzma(Lzma),
+	Zlib(Zlib),
+	Zstd(Zstd),
+	Brotli(Brotli),
+}
+
+impl ArchiveCompressor {
+	/// The compressor with the given name, at the given level (or quality) and window size, or the default ones if not given
+	pub fn new(name: &str, level: Option<i32>, window: Option<u32>) -> anyhow::Result<ArchiveCompressor> {
+		let level_in = |range: std::ops::RangeInclusive<i32>, default: i32| match level {
+			Some(level) if !range.contains(&level) => {
+				bail!("{} level {} is outside {}..={}", name, level, range.start(), range.end())
+			}
+			Some(level) => Ok(level),
+			Option::None => Ok(default),
+		};
+		if window.is_some() && name != "brotli" {
+			bail!("Only brotli has a window size");
+		}
+		Ok(match name {
+			"none" => ArchiveCompressor::None(None),
+			"lzma" => ArchiveCompressor::Lzma(Lzma {
+				level: level_in(0..=9, 9)? as u32,
+			}),
+			"zlib" => ArchiveCompressor::Zlib(Zlib {
+				level: level_in(0..=9, 9)? as u32,
+			}),
+			"zstd" => ArchiveCompressor::Zstd(Zstd {
+				level: level_in(1..=22, 19)?,
+			}),
+			"brotli" => {
+				let lgwin = window.unwrap_or(22);
+				if !(10..=24).contains(&lgwin) {
+					bail!("brotli window {} is outside 10..=24", lgwin);
+				}
+				ArchiveCompressor::Brotli(Brotli {
+					quality: level_in(0..=11, 11)? as u32,
+					lgwin,
+				})
+			}
+			_ => bail!("Unknown compressor {}, expected none, lzma, zlib, zstd or brotli", name),
+		})
+	}
+
+	/// Identifies the compressor in archive headers
+	pub fn id(&self) -> u8 {
+		match self {
+			ArchiveCompressor::None(_) => 0,
+			ArchiveCompressor::Lzma(_) => 1,
+			ArchiveCompressor::Zlib(_) => 2,
+			ArchiveCompressor::Zstd(_) => 3,
+			ArchiveCompressor::Brotli(_) => 4,
+		}
+	}
+
+	/// A compressor that can decompress what the compressor with the id wrote
+	pub fn from_header(id: u8) -> anyhow::Result<ArchiveCompressor> {
+		Ok(match id {
+			0 => ArchiveCompressor::None(None),
+			1 => ArchiveCompressor::Lzma(Lzma::default()),
+			2 => ArchiveCompressor::Zlib(Zlib::default()),
+			3 => ArchiveCompressor::Zstd(Zstd::default()),
+			4 => ArchiveCompressor::Brotli(Brotli::default()),
+			_ => bail!("Unknown compressor {}", id),
+		})
+	}
+
+	fn inner(&self) -> &dyn ByteCompressor {
+		match self {
+			ArchiveCompressor::None(compressor) => compressor,
+			ArchiveCompressor::Lzma(compressor) => compressor,
+			ArchiveCompressor::Zlib(compressor) => compressor,
+			ArchiveCompressor::Zstd(compressor) => compressor,
+			ArchiveCompressor::Brotli(compressor) => compressor,
+		}
+	}
+}
+
+impl ByteCompressor for ArchiveCompressor {
+	fn compress(&self, data: &[u8], dest: &mut Vec<u8>) {
+		self.inner().compress(data, dest)
+	}
+
+	fn decompress(&self, data: &[u8], dest: &mut Vec<u8>) -> io::Result<()> {
+		self.inner().decompress(data, dest)
+	}
+}
+
+impl fmt::Display for ArchiveCompressor {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ArchiveCompressor::None(_) => write!(f, "None"),
+			ArchiveCompressor::Lzma(Lzma { level }) => write!(f, "LZMA level {}", level),
+			ArchiveCompressor::Zlib(Zlib { level }) => write!(f, "Zlib level {}", level),
+			ArchiveCompressor::Zstd(Zstd { level }) => write!(f, "Zstd level {}", level),
+			ArchiveCompressor::Brotli(Brotli { quality, lgwin }) => write!(f, "Brotli quality {}, window {}", quality, lgwin),
+		}
+	}
+}
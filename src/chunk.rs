@@ -0,0 +1,398 @@
+use nbt::{Map, Value};
+
+use crate::util::Packing;
+
+/// Data version of 17w47a (1.13), which replaced numeric block IDs with block state palettes
+const DATA_VERSION_FLATTENING: i32 = 1451;
+/// Data version of 20w17a (1.16), which stopped packed values from spanning two longs
+const DATA_VERSION_PADDED_PACKING: i32 = 2529;
+/// Data version of 21w43a (1.18), which removed the Level compound and moved block states into block_states
+const DATA_VERSION_NO_LEVEL: i32 = 2844;
+
+/// The keys of the nibble arrays holding a section's light levels, in every version
+pub const LIGHT_KEYS: [&str; 2] = ["BlockLight", "SkyLight"];
+
+/// The chunk layout, selected from the DataVersion of the chunk
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkVersion(pub i32);
+
+/// A section's block state (or 1.18+ biome) palette and packed indices;
+/// data is missing for 1.18+ sections with a single palette entry
+pub struct BlockStates<'a> {
+	pub palette: &'a Vec<Value>,
+	pub data: Option<&'a Vec<i64>>,
+}
+
+pub struct BlockStatesMut<'a> {
+	pub palette: &'a mut Vec<Value>,
+	pub data: Option<&'a mut Vec<i64>>,
+}
+
+/// A pre-flattening section's blocks: 8 bit IDs in Blocks, with the metadata in Data and the optional top 4 bits of the ID in Add
+pub struct LegacyBlocks<'a> {
+	pub blocks: &'a Vec<i8>,
+	pub data: &'a Vec<i8>,
+	pub add: Option<&'a Vec<i8>>,
+}
+
+pub struct LegacyBlocksMut<'a> {
+	pub blocks: &'a mut Vec<i8>,
+	pub data: &'a mut Vec<i8>,
+	pub add: Option<&'a mut Vec<i8>>,
+}
+
+impl ChunkVersion {
+	pub fn of(chunk: &Value) -> ChunkVersion {
+		match chunk {
+			Value::Compound(root) => match root.get("DataVersion") {
+				Some(Value::Int(version)) => ChunkVersion(*version),
+				// Chunks from before 1.9 have no DataVersion
+				_ => ChunkVersion(0),
+			},
+			_ => ChunkVersion(0),
+		}
+	}
+
+	/// Whether sections use block state palettes rather than numeric IDs
+	pub fn is_flattened(self) -> bool {
+		self.0 >= DATA_VERSION_FLATTENING
+	}
+
+	/// The layout of packed long arrays such as BlockStates and Heightmaps
+	pub fn packing(self) -> Packing {
+		if self.0 < DATA_VERSION_PADDED_PACKING {
+			Packing::Spanning
+		} else {
+			Packing::Padded
+		}
+	}
+
+	fn has_level(self) -> bool {
+		self.0 < DATA_VERSION_NO_LEVEL
+	}
+
+	/// The compound holding the chunk data: Level before 1.18, the root compound afterwards
+	pub fn level(self, chunk: &Value) -> Option<&Map<String, Value>> {
+		match chunk {
+			Value::Compound(root) if self.has_level() => match root.get("Level") {
+				Some(Value::Compound(level)) => Some(level),
+				_ => None,
+			},
+			Value::Compound(root) => Some(root),
+			_ => None,
+		}
+	}
+
+	pub fn level_mut(self, chunk: &mut Value) -> Option<&mut Map<String, Value>> {
+		let root = match chunk {
+			Value::Compound(root) => root,
+			_ => return None,
+		};
+		if !self.has_level() {
+			return Some(root);
+		}
+		match root.get_mut("Level") {
+			Some(Value::Compound(level)) => Some(level),
+			_ => None,
+		}
+	}
+
+	fn sections_key(self) -> &'static str {
+		if self.has_level() {
+			"Sections"
+		} else {
+			"sections"
+		}
+	}
+
+	pub fn sections(self, chunk: &Value) -> Option<&Vec<Value>> {
+		match self.level(chunk)?.get(self.sections_key()) {
+			Some(Value::List(sections)) => Some(sections),
+			_ => None,
+		}
+	}
+
+	pub fn sections_mut(self, chunk: &mut Value) -> Option<&mut Vec<Value>> {
+		match self.level_mut(chunk)?.get_mut(self.sections_key()) {
+			Some(Value::List(sections)) => Some(sections),
+			_ => None,
+		}
+	}
+
+	/// The lowest block Y and the height of the world, which are fixed before 1.18.
+	/// From 1.18 they come from yPos and the highest section with block states, as sections above and below only hold light.
+	pub fn world_bounds(self, chunk: &Value) -> Option<(i32, i32)> {
+		if self.has_level() {
+			return Some((0, 256));
+		}
+		let min_section = match self.level(chunk)?.get("yPos") {
+			Some(Value::Int(y)) => *y,
+			_ => return None,
+		};
+		let max_section = self
+			.sections(chunk)?
+			.iter()
+			.filter(|section| self.block_states(section).is_some())
+			.filter_map(|section| match section {
+				Value::Compound(section) => match section.get("Y") {
+					Some(Value::Byte(y)) => Some(*y as i32),
+					_ => None,
+				},
+				_ => None,
+			})
+			.max()?;
+		Some((min_section * 16, (max_section + 1 - min_section) * 16))
+	}
+
+	pub fn heightmaps_mut(self, chunk: &mut Value) -> Option<&mut Map<String, Value>> {
+		match self.level_mut(chunk)?.get_mut("Heightmaps") {
+			Some(Value::Compound(heightmaps)) => Some(heightmaps),
+			_ => None,
+		}
+	}
+
+	/// The compound holding the palette and data keys, along with their names
+	fn block_states_container(self, section: &Value) -> Option<(&Map<String, Value>, &'static str, &'static str)> {
+		match section {
+			Value::Compound(section) if self.has_level() => Some((section, "Palette", "BlockStates")),
+			Value::Compound(section) => match section.get("block_states") {
+				Some(Value::Compound(block_states)) => Some((block_states, "palette", "data")),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
+
+	fn block_states_container_mut(self, section: &mut Value) -> Option<(&mut Map<String, Value>, &'static str, &'static str)> {
+		let section = match section {
+			Value::Compound(section) => section,
+			_ => return None,
+		};
+		if self.has_level() {
+			return Some((section, "Palette", "BlockStates"));
+		}
+		match section.get_mut("block_states") {
+			Some(Value::Compound(block_states)) => Some((block_states, "palette", "data")),
+			_ => None,
+		}
+	}
+
+	pub fn block_states(self, section: &Value) -> Option<BlockStates<'_>> {
+		let (container, palette_key, data_key) = self.block_states_container(section)?;
+		paletted_container(container, palette_key, data_key)
+	}
+
+	pub fn block_states_mut(self, section: &mut Value) -> Option<BlockStatesMut<'_>> {
+		let (container, palette_key, data_key) = self.block_states_container_mut(section)?;
+		paletted_container_mut(container, palette_key, data_key)
+	}
+
+	/// A 1.18+ section's biomes, a 4x4x4 grid packed the same way as its block states
+	pub fn biomes(self, section: &Value) -> Option<BlockStates<'_>> {
+		match section {
+			Value::Compound(section) if !self.has_level() => match section.get("biomes") {
+				Some(Value::Compound(biomes)) => paletted_container(biomes, "palette", "data"),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
+
+	pub fn biomes_mut(self, section: &mut Value) -> Option<BlockStatesMut<'_>> {
+		match section {
+			Value::Compound(section) if !self.has_level() => match section.get_mut("biomes") {
+				Some(Value::Compound(biomes)) => paletted_container_mut(biomes, "palette", "data"),
+				_ => None,
+			},
+			_ => None,
+		}
+	}
+
+	pub fn legacy_blocks(self, section: &Value) -> Option<LegacyBlocks<'_>> {
+		match section {
+			Value::Compound(section) if !self.is_flattened() => {
+				let blocks = match section.get("Blocks") {
+					Some(Value::ByteArray(blocks)) => blocks,
+					_ => return None,
+				};
+				let data = match section.get("Data") {
+					Some(Value::ByteArray(data)) => data,
+					_ => return None,
+				};
+				let add = match section.get("Add") {
+					Some(Value::ByteArray(add)) => Some(add),
+					_ => None,
+				};
+				Some(LegacyBlocks { blocks, data, add })
+			}
+			_ => None,
+		}
+	}
+
+	pub fn legacy_blocks_mut(self, section: &mut Value) -> Option<LegacyBlocksMut<'_>> {
+		match section {
+			Value::Compound(section) if !self.is_flattened() => {
+				let mut blocks = None;
+				let mut data = None;
+				let mut add = None;
+				for (key, value) in section.iter_mut() {
+					match (key.as_str(), value) {
+						("Blocks", Value::ByteArray(array)) => blocks = Some(array),
+						("Data", Value::ByteArray(array)) => data = Some(array),
+						("Add", Value::ByteArray(array)) => add = Some(array),
+						_ => {}
+					}
+				}
+				Some(LegacyBlocksMut {
+					blocks: blocks?,
+					data: data?,
+					add,
+				})
+			}
+			_ => None,
+		}
+	}
+}
+
+fn paletted_container<'a>(container: &'a Map<String, Value>, palette_key: &str, data_key: &str) -> Option<BlockStates<'a>> {
+	let palette = match container.get(palette_key) {
+		Some(Value::List(palette)) => palette,
+		_ => return None,
+	};
+	let data = match container.get(data_key) {
+		Some(Value::LongArray(data)) => Some(data),
+		_ => None,
+	};
+	Some(BlockStates { palette, data })
+}
+
+fn paletted_container_mut<'a>(
+	container: &'a mut Map<String, Value>,
+	palette_key: &str,
+	data_key: &str,
+) -> Option<BlockStatesMut<'a>> {
+	let mut palette = None;
+	let mut data = None;
+	for (key, value) in container.iter_mut() {
+		match value {
+			Value::List(list) if key == palette_key => palette = Some(list),
+			Value::LongArray(array) if key == data_key => data = Some(array),
+			_ => {}
+		}
+	}
+	Some(BlockStatesMut { palette: palette?, data })
+}
+
+/// Converts pre-flattening block arrays into a local palette of (ID << 4 | metadata) states and indices into it,
+/// or None if the arrays are the wrong size
+pub fn legacy_to_palette(blocks: &[i8], data: &[i8], add: Option<&[i8]>) -> Option<(Vec<u16>, [u32; 4096])> {
+	if blocks.len() != 4096 || data.len() != 2048 || add.is_some_and(|add| add.len() != 2048) {
+		return None;
+	}
+
+	let mut palette: Vec<u16> = vec![];
+	let mut arr = [0u32; 4096];
+	for (i, v) in arr.iter_mut().enumerate() {
+		let add_bits = add.map_or(0, |add| nibble(add, i)) as u16;
+		let state = (add_bits << 12) | ((blocks[i] as u8 as u16) << 4) | nibble(data, i) as u16;
+		*v = match palette.iter().position(|s| *s == state) {
+			Some(pos) => pos as u32,
+			None => {
+				palette.push(state);
+				palette.len() as u32 - 1
+			}
+		};
+	}
+	Some((palette, arr))
+}
+
+/// Rebuilds the Blocks, Data and (if has_add) Add arrays from a palette produced by legacy_to_palette
+pub fn legacy_from_palette(palette: &[u16], arr: &[u32; 4096], has_add: bool) -> (Vec<i8>, Vec<i8>, Option<Vec<i8>>) {
+	let mut blocks = vec![0i8; 4096];
+	let mut data = vec![0i8; 2048];
+	let mut add = vec![0i8; 2048];
+	for (i, v) in arr.iter().enumerate() {
+		let state = palette[*v as usize];
+		blocks[i] = (state >> 4) as u8 as i8;
+		set_nibble(&mut data, i, (state & 0xF) as u8);
+		set_nibble(&mut add, i, (state >> 12) as u8);
+	}
+	(blocks, data, if has_add { Some(add) } else { None })
+}
+
+/// Unpacks a section's 2048 byte nibble array, such as BlockLight, into its 4096 values
+pub fn unpack_nibbles(array: &[i8]) -> [u32; 4096] {
+	let mut arr = [0u32; 4096];
+	for (i, v) in arr.iter_mut().enumerate() {
+		*v = nibble(array, i) as u32;
+	}
+	arr
+}
+
+pub fn pack_nibbles(arr: &[u32]) -> Vec<i8> {
+	let mut array = vec![0i8; arr.len() / 2];
+	for (i, v) in arr.iter().enumerate() {
+		set_nibble(&mut array, i, *v as u8);
+	}
+	array
+}
+
+pub fn nibble(array: &[i8], i: usize) -> u8 {
+	let byte = array[i / 2] as u8;
+	if i & 1 == 0 {
+		byte & 0xF
+	} else {
+		byte >> 4
+	}
+}
+
+pub fn set_nibble(array: &mut [i8], i: usize, value: u8) {
+	let byte = array[i / 2] as u8;
+	array[i / 2] = if i & 1 == 0 {
+		(byte & 0xF0) | value
+	} else {
+		(byte & 0x0F) | (value << 4)
+	} as i8;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Blocks with IDs cycling through every byte value, metadata through every nibble and, with add, IDs above 255
+	fn legacy_arrays(with_add: bool) -> (Vec<i8>, Vec<i8>, Option<Vec<i8>>) {
+		let blocks: Vec<i8> = (0..4096).map(|i| (i % 7 * 37) as u8 as i8).collect();
+		let mut data = vec![0i8; 2048];
+		let mut add = vec![0i8; 2048];
+		for i in 0..4096 {
+			set_nibble(&mut data, i, (i % 5) as u8);
+			set_nibble(&mut add, i, (i / 1024) as u8);
+		}
+		(blocks, data, if with_add { Some(add) } else { None })
+	}
+
+	#[test]
+	fn legacy_palette_round_trips() {
+		for with_add in [false, true] {
+			let (blocks, data, add) = legacy_arrays(with_add);
+			let (palette, arr) = legacy_to_palette(&blocks, &data, add.as_deref()).unwrap();
+			assert_eq!(palette[0], 0);
+			assert_eq!(legacy_from_palette(&palette, &arr, with_add), (blocks, data, add));
+		}
+	}
+
+	#[test]
+	fn legacy_palette_rejects_wrong_sizes() {
+		let (blocks, data, _) = legacy_arrays(false);
+		assert!(legacy_to_palette(&blocks[..4095], &data, None).is_none());
+		assert!(legacy_to_palette(&blocks, &data, Some(&data[..16])).is_none());
+	}
+
+	#[test]
+	fn nibbles_round_trip() {
+		let arr: Vec<u32> = (0..4096).map(|i| i % 16).collect();
+		let packed = pack_nibbles(&arr);
+		assert_eq!(packed[0], 0x10);
+		assert_eq!(unpack_nibbles(&packed).to_vec(), arr);
+	}
+}
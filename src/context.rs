@@ -0,0 +1,165 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use nbt::Value;
+
+use crate::archive::unpack_block_states;
+use crate::chunk::{legacy_to_palette, BlockStates, ChunkVersion, LegacyBlocks};
+
+/// Stands in for values whose palette entry isn't in the palette of the section being coded
+pub const NO_PALETTE_ENTRY: u32 = u32::MAX;
+
+/// What is known about a section before its values are decoded: its palette entries,
+/// and the already coded sections next to it with their values mapped into its palette
+#[derive(Default)]
+pub struct SectionContext {
+	pub palette: Vec<Value>,
+	/// The section below in the same chunk
+	pub below: Option<Vec<u32>>,
+	/// The section at the same height in the chunk to the west (-X)
+	pub west: Option<Vec<u32>>,
+	/// The section at the same height in the chunk to the north (-Z)
+	pub north: Option<Vec<u32>>,
+	/// The same section in the snapshot a delta archive is made against
+	pub previous: Option<Vec<u32>>,
+	/// Initial model counts for each palette entry, from the priors the archive is written with
+	pub prior_counts: Option<Vec<u32>>,
+}
+
+impl SectionContext {
+	/// Renumbers the palette so the entries the priors make likeliest come first,
+	/// as transformers like MoveToFront start from the palette order.
+	/// Returns the palette index of each new index, and the context with its neighbours and prior counts renumbered to match.
+	pub fn ranked_by_priors(&self) -> Option<(Vec<u32>, SectionContext)> {
+		let counts = self.prior_counts.as_ref()?;
+		// Stable, so equally likely entries keep their relative order
+		let mut order: Vec<u32> = (0..counts.len() as u32).collect();
+		order.sort_by_key(|v| Reverse(counts[*v as usize]));
+		let mut new_index = vec![0u32; counts.len()];
+		for (i, v) in order.iter().enumerate() {
+			new_index[*v as usize] = i as u32;
+		}
+		let rank = |arr: &Option<Vec<u32>>| {
+			arr.as_ref().map(|arr| {
+				arr.iter()
+					.map(|v| new_index.get(*v as usize).copied().unwrap_or(NO_PALETTE_ENTRY))
+					.collect()
+			})
+		};
+		let context = SectionContext {
+			palette: order.iter().map(|v| self.palette[*v as usize].clone()).collect(),
+			below: rank(&self.below),
+			west: rank(&self.west),
+			north: rank(&self.north),
+			previous: rank(&self.previous),
+			prior_counts: Some(order.iter().map(|v| counts[*v as usize]).collect()),
+		};
+		Some((order, context))
+	}
+}
+
+struct CodedSection {
+	palette: Vec<Value>,
+	arr: [u32; 4096],
+}
+
+/// The sections coded so far, in the order the decoder sees them.
+/// Chunks must be added in increasing index order; only the last row of chunks is kept as the west and north neighbours.
+pub struct SectionHistory {
+	chunks: BTreeMap<u16, HashMap<i8, CodedSection>>,
+}
+
+impl SectionHistory {
+	pub fn new() -> SectionHistory {
+		SectionHistory { chunks: BTreeMap::new() }
+	}
+
+	/// The context for a section, which has no neighbouring sections if the section has no height
+	pub fn context(&self, chunk_index: u16, y: Option<i8>, palette: &[Value]) -> SectionContext {
+		let y = match y {
+			Some(y) => y,
+			None => {
+				return SectionContext {
+					palette: palette.to_vec(),
+					..SectionContext::default()
+				}
+			}
+		};
+		let find = |chunk_index: u16, y: i8| {
+			let section = self.chunks.get(&chunk_index)?.get(&y)?;
+			Some(map_to_palette(&section.arr, &section.palette, palette))
+		};
+		SectionContext {
+			palette: palette.to_vec(),
+			below: y.checked_sub(1).and_then(|below_y| find(chunk_index, below_y)),
+			// Chunks at the edge of the region have no neighbours in it
+			west: if chunk_index & 31 > 0 {
+				find(chunk_index - 1, y)
+			} else {
+				None
+			},
+			north: if chunk_index >= 32 { find(chunk_index - 32, y) } else { None },
+			previous: None,
+			prior_counts: None,
+		}
+	}
+
+	pub fn insert(&mut self, chunk_index: u16, y: i8, palette: Vec<Value>, arr: [u32; 4096]) {
+		if !self.chunks.contains_key(&chunk_index) {
+			self.chunks = self.chunks.split_off(&chunk_index.saturating_sub(32));
+		}
+		self.chunks
+			.entry(chunk_index)
+			.or_default()
+			.insert(y, CodedSection { palette, arr });
+	}
+}
+
+/// Maps values from one palette into another by comparing their palette entries
+pub fn map_to_palette(arr: &[u32], from_palette: &[Value], to_palette: &[Value]) -> Vec<u32> {
+	let mapping: Vec<u32> = from_palette
+		.iter()
+		.map(|entry| {
+			to_palette
+				.iter()
+				.position(|to_entry| to_entry == entry)
+				.map_or(NO_PALETTE_ENTRY, |pos| pos as u32)
+		})
+		.collect();
+	arr.iter().map(|v| mapping[*v as usize]).collect()
+}
+
+/// A section's height, palette entries and values, for any section whose blocks can be read.
+/// Pre-flattening palette entries are the numeric states from legacy_to_palette.
+pub fn section_blocks(version: ChunkVersion, section: &Value) -> Option<(i8, Vec<Value>, [u32; 4096])> {
+	let y = section_y(section)?;
+	if let Some(BlockStates { palette, data }) = version.block_states(section) {
+		let arr = match data {
+			Some(data) if !data.is_empty() => unpack_block_states(data, palette.len() as u32, version.packing())?,
+			// Sections with a single palette entry have no data
+			_ if palette.len() == 1 => [0u32; 4096],
+			_ => return None,
+		};
+		Some((y, palette.clone(), arr))
+	} else if let Some(LegacyBlocks { blocks, data, add }) = version.legacy_blocks(section) {
+		let (palette, arr) = legacy_to_palette(blocks, data, add.map(Vec::as_slice))?;
+		Some((y, legacy_palette_entries(&palette), arr))
+	} else {
+		None
+	}
+}
+
+pub fn section_y(section: &Value) -> Option<i8> {
+	match section {
+		Value::Compound(section) => match section.get("Y") {
+			Some(Value::Byte(y)) => Some(*y),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+/// Palette entries for the numeric states of a pre-flattening section's local palette
+pub fn legacy_palette_entries(palette: &[u16]) -> Vec<Value> {
+	palette.iter().map(|state| Value::Short(*state as i16)).collect()
+}
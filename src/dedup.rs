@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::util::{read_varint, write_varint};
+
+/// How a payload that may be written more than once is stored: by itself, as a copy the decoder keeps for later occurrences,
+/// or as a reference to a kept copy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Occurrence {
+	Unique,
+	Kept,
+	/// The index of the kept copy, counting kept copies in the order they were written
+	Reference(u64),
+}
+
+impl Occurrence {
+	pub fn write(self, dest: &mut impl Write) -> io::Result<()> {
+		write_varint(
+			dest,
+			match self {
+				Occurrence::Unique => 0,
+				Occurrence::Kept => 1,
+				Occurrence::Reference(index) => index + 2,
+			},
+		)
+	}
+
+	pub fn read(src: &mut impl Read) -> io::Result<Occurrence> {
+		Ok(match read_varint(src)? {
+			0 => Occurrence::Unique,
+			1 => Occurrence::Kept,
+			value => Occurrence::Reference(value - 2),
+		})
+	}
+}
+
+/// Finds payloads with identical contents. Every payload is counted first,
+/// so the decoder only has to keep the copies that are referenced later.
+#[derive(Default)]
+pub struct Repeats {
+	/// The number of occurrences of each payload, and the index of its kept copy once it has been written
+	counts: HashMap<Vec<u8>, (u64, Option<u64>)>,
+	kept: u64,
+}
+
+impl Repeats {
+	pub fn new() -> Repeats {
+		Repeats::default()
+	}
+
+	/// Counts a payload, returning whether it repeats an earlier one
+	pub fn count(&mut self, key: Vec<u8>) -> bool {
+		let (count, _) = self.counts.entry(key).or_insert((0, None));
+		*count += 1;
+		*count > 1
+	}
+
+	/// How to write the next occurrence of a counted payload
+	pub fn occurrence(&mut self, key: &[u8]) -> Occurrence {
+		match self.counts.get_mut(key) {
+			Some((count, _)) if *count <= 1 => Occurrence::Unique,
+			Some((_, Some(index))) => Occurrence::Reference(*index),
+			Some((_, kept)) => {
+				*kept = Some(self.kept);
+				self.kept += 1;
+				Occurrence::Kept
+			}
+			None => Occurrence::Unique,
+		}
+	}
+
+	/// The number of payloads counted, and how many of them repeat an earlier one
+	pub fn duplicates(&self) -> (u64, u64) {
+		let total = self.counts.values().map(|(count, _)| count).sum();
+		(total, total - self.counts.len() as u64)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	#[test]
+	fn occurrences_round_trip() {
+		let occurrences = [
+			Occurrence::Unique,
+			Occurrence::Kept,
+			Occurrence::Reference(0),
+			Occurrence::Reference(300),
+		];
+		let mut bytes = vec![];
+		for occurrence in occurrences {
+			occurrence.write(&mut bytes).unwrap();
+		}
+		let mut src = Cursor::new(bytes);
+		for occurrence in occurrences {
+			assert_eq!(Occurrence::read(&mut src).unwrap(), occurrence);
+		}
+	}
+
+	#[test]
+	fn only_repeated_payloads_are_kept() {
+		let payloads: [&[u8]; 5] = [b"a", b"b", b"a", b"c", b"a"];
+		let mut repeats = Repeats::new();
+		for payload in payloads {
+			repeats.count(payload.to_vec());
+		}
+		assert_eq!(repeats.duplicates(), (5, 2));
+		let occurrences: Vec<Occurrence> = payloads.iter().map(|payload| repeats.occurrence(payload)).collect();
+		assert_eq!(
+			occurrences,
+			[
+				Occurrence::Kept,
+				Occurrence::Unique,
+				Occurrence::Reference(0),
+				Occurrence::Unique,
+				Occurrence::Reference(0)
+			]
+		);
+	}
+}
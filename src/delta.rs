@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use flate2::Crc;
+use nbt::Value;
+
+use crate::chunk::ChunkVersion;
+use crate::context::{map_to_palette, section_blocks, section_y, SectionContext, NO_PALETTE_ENTRY};
+use crate::region::Chunk;
+
+/// The snapshot a delta archive is made against: the chunks restored from a full archive and the deltas after it,
+/// along with a checksum of the last archive in that chain, so deltas can't be restored onto the wrong one
+pub struct Base {
+	chunks: HashMap<u16, Chunk>,
+	checksum: u32,
+}
+
+impl Base {
+	pub fn new(chunks: Vec<Chunk>, archive: &[u8]) -> Base {
+		let mut crc = Crc::new();
+		crc.update(archive);
+		Base {
+			chunks: chunks.into_iter().map(|chunk| (chunk.index, chunk)).collect(),
+			checksum: crc.sum(),
+		}
+	}
+
+	pub fn checksum(&self) -> u32 {
+		self.checksum
+	}
+
+	pub fn chunk(&self, index: u16) -> Option<&Chunk> {
+		self.chunks.get(&index)
+	}
+
+	/// Whether a chunk's NBT is the same as in the base apart from its tick counts, so it can be stored as a reference to it,
+	/// returning how much each tick count changed.
+	/// Archives restore chunks exactly, including those written with --strip-derived,
+	/// so chunks restored from the base compare equal.
+	pub fn unchanged(&self, chunk: &Chunk) -> Option<[i64; 2]> {
+		let base_chunk = self.chunk(chunk.index)?;
+		if base_chunk.data == chunk.data {
+			return Some([0, 0]);
+		}
+		let base_counts = tick_counts(&base_chunk.data);
+		let counts = tick_counts(&chunk.data);
+		let mut data = chunk.data.clone();
+		set_tick_counts(&mut data, base_counts);
+		(data == base_chunk.data).then(|| [0, 1].map(|i| counts[i].unwrap_or(0).wrapping_sub(base_counts[i].unwrap_or(0))))
+	}
+
+	/// The NBT of a chunk that unchanged found to be the same as in the base, with its tick counts changed back
+	pub fn unchanged_chunk(&self, index: u16, tick_changes: [i64; 2]) -> anyhow::Result<Value> {
+		let mut data = self
+			.chunk(index)
+			.context("Unchanged chunk missing from the base")?
+			.data
+			.clone();
+		let base_counts = tick_counts(&data);
+		if (0..2).any(|i| tick_changes[i] != 0 && base_counts[i].is_none()) {
+			bail!("Tick count missing from the base chunk");
+		}
+		set_tick_counts(
+			&mut data,
+			[0, 1].map(|i| base_counts[i].map(|count| count.wrapping_add(tick_changes[i]))),
+		);
+		Ok(data)
+	}
+
+	/// The blocks of the section at the same height in the base's version of a chunk, mapped into the palette of the new section
+	pub fn previous_section(&self, chunk_index: u16, y: Option<i8>, palette: &[Value]) -> Option<Vec<u32>> {
+		let data = &self.chunk(chunk_index)?.data;
+		let version = ChunkVersion::of(data);
+		let section = version
+			.sections(data)?
+			.iter()
+			.find(|section| y.is_some() && section_y(section) == y)?;
+		let (_, previous_palette, arr) = section_blocks(version, section)?;
+		Some(map_to_palette(&arr, &previous_palette, palette))
+	}
+
+	/// The context for coding a section as its changes from the base, if the base has the section.
+	/// The neighbouring sections are their own changes from the base, so neighbours that didn't change are 0 like the section's
+	/// unchanged blocks, and neighbours missing from the base are shifted up by one like changed blocks.
+	pub fn changes_context(&self, context: &SectionContext, chunk_index: u16, y: Option<i8>) -> Option<SectionContext> {
+		let palette = &context.palette;
+		let changes = |neighbour: &Option<Vec<u32>>, chunk_index: Option<u16>, y: Option<i8>| {
+			let previous = chunk_index.and_then(|chunk_index| self.previous_section(chunk_index, y, palette));
+			neighbour.as_ref().map(|neighbour| {
+				(0..neighbour.len())
+					.map(|i| match (neighbour[i], previous.as_ref().map(|previous| previous[i])) {
+						(NO_PALETTE_ENTRY, _) => NO_PALETTE_ENTRY,
+						(value, Some(previous)) if value == previous => 0,
+						(value, _) => value + 1,
+					})
+					.collect()
+			})
+		};
+		Some(SectionContext {
+			palette: palette.clone(),
+			below: changes(&context.below, Some(chunk_index), y.and_then(|y| y.checked_sub(1))),
+			west: changes(&context.west, (chunk_index & 31 > 0).then(|| chunk_index - 1), y),
+			north: changes(&context.north, chunk_index.checked_sub(32), y),
+			previous: Some(self.previous_section(chunk_index, y, palette)?),
+			// The prior counts are for the palette entries, which the changes are shifted from
+			prior_counts: None,
+		})
+	}
+}
+
+/// Counts that change whenever a chunk is loaded, even if nothing in it changes
+const TICK_KEYS: [&str; 2] = ["LastUpdate", "InhabitedTime"];
+
+/// The tick counts of a chunk that has them
+fn tick_counts(data: &Value) -> [Option<i64>; 2] {
+	let level = ChunkVersion::of(data).level(data);
+	TICK_KEYS.map(|key| match level?.get(key) {
+		Some(Value::Long(count)) => Some(*count),
+		_ => None,
+	})
+}
+
+/// Replaces the tick counts a chunk has
+fn set_tick_counts(data: &mut Value, counts: [Option<i64>; 2]) {
+	if let Some(level) = ChunkVersion::of(data).level_mut(data) {
+		for (key, count) in TICK_KEYS.iter().zip(counts) {
+			if let (Some(Value::Long(value)), Some(count)) = (level.get_mut(*key), count) {
+				*value = count;
+			}
+		}
+	}
+}
+
+/// Replaces values that are the same as in the section's previous version with 0, shifting the others up by one,
+/// so a section with few changes is mostly zeroes
+pub fn changes_from(arr: &mut [u32], previous: &[u32]) {
+	for (value, previous) in arr.iter_mut().zip(previous) {
+		*value = if value == previous { 0 } else { *value + 1 };
+	}
+}
+
+/// Reverses changes_from
+pub fn apply_changes(arr: &mut [u32], previous: &[u32]) {
+	for (value, previous) in arr.iter_mut().zip(previous) {
+		*value = if *value == 0 { *previous } else { *value - 1 };
+	}
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+
+use nbt::Value;
+
+use crate::blocks::{block_properties, BlockProperties};
+use crate::chunk::{set_nibble, ChunkVersion, LIGHT_KEYS};
+use crate::context::{section_blocks, section_y};
+use crate::util::pack_integers;
+
+// Heightmaps and light can be recomputed from block states, so archives can leave them out.
+// They are only left out when recomputing them gives back exactly the same data, so they are restored losslessly.
+// Light is recomputed within each chunk, without light spreading in from neighbouring chunks, so sections lit from
+// the chunks next to them, or with blocks missing from the block property table, keep their light.
+// Stripped arrays are emptied and left in place like stripped block states, keeping the NBT's key order;
+// archives record which ones were stripped, as arrays can also be empty to begin with.
+
+/// The heightmaps and light arrays strip_derived emptied, which restore_derived recomputes
+#[derive(Default)]
+pub struct StrippedDerived {
+	/// Positions in the chunk's Heightmaps compound
+	pub heightmaps: Vec<usize>,
+	/// Positions in the chunk's section list, and the light key
+	pub light: Vec<(usize, &'static str)>,
+}
+
+/// Which blocks count towards a heightmap, or None for heightmaps that can't be recomputed
+fn heightmap_predicate(key: &str) -> Option<fn(&BlockProperties) -> bool> {
+	match key {
+		"WORLD_SURFACE" | "WORLD_SURFACE_WG" => Some(|block| !block.is_air),
+		"OCEAN_FLOOR" | "OCEAN_FLOOR_WG" => Some(|block| block.blocks_motion),
+		"MOTION_BLOCKING" => Some(|block| block.blocks_motion || block.has_fluid),
+		"MOTION_BLOCKING_NO_LEAVES" => Some(|block| (block.blocks_motion || block.has_fluid) && !block.is_leaves),
+		"LIGHT_BLOCKING" => Some(|block| block.opacity > 0),
+		_ => None,
+	}
+}
+
+/// The block properties of every section from the lowest to the highest in a chunk's section list,
+/// ordered like block states, with sections without block states as air
+struct ChunkBlocks {
+	min_section: i32,
+	blocks: Vec<BlockProperties>,
+}
+
+impl ChunkBlocks {
+	fn new(version: ChunkVersion, chunk: &Value) -> Option<ChunkBlocks> {
+		let sections = version.sections(chunk)?;
+		let min_section = sections.iter().filter_map(section_y).min()? as i32;
+		let max_section = sections.iter().filter_map(section_y).max()? as i32;
+		let mut blocks = vec![BlockProperties::AIR; (max_section - min_section + 1) as usize * 4096];
+		for section in sections {
+			if let Some((y, palette, arr)) = section_blocks(version, section) {
+				let properties: Vec<BlockProperties> = palette.iter().map(block_properties).collect();
+				let offset = (y as i32 - min_section) as usize * 4096;
+				for (block, v) in blocks[offset..offset + 4096].iter_mut().zip(arr.iter()) {
+					*block = properties[*v as usize];
+				}
+			}
+		}
+		Some(ChunkBlocks { min_section, blocks })
+	}
+
+	fn height(&self) -> usize {
+		self.blocks.len() / 256
+	}
+
+	/// The packed heightmap for a key, counting up from min_y
+	fn heightmap(&self, version: ChunkVersion, key: &str, (min_y, height): (i32, i32)) -> Option<Vec<i64>> {
+		let predicate = heightmap_predicate(key)?;
+		let values: Vec<u32> = (0..256)
+			.map(|column| {
+				(0..self.height())
+					.rev()
+					.find(|y| predicate(&self.blocks[y * 256 + column]))
+					.map_or(0, |y| (self.min_section * 16 + y as i32 + 1 - min_y).max(0) as u32)
+			})
+			.collect();
+		let bits = ((height + 1) as f64).log2().ceil() as u8;
+		Some(pack_integers(&values, bits, version.packing()))
+	}
+
+	/// Sky light falls straight down until it reaches a block that isn't fully transparent,
+	/// or block light starts at emitting blocks, then spreads out losing at least one level per block
+	fn light(&self, sky: bool) -> Vec<u8> {
+		let mut light = vec![0u8; self.blocks.len()];
+		let mut queue = VecDeque::new();
+		if sky {
+			for column in 0..256 {
+				for y in (0..self.height()).rev() {
+					let i = y * 256 + column;
+					if self.blocks[i].opacity > 0 {
+						break;
+					}
+					light[i] = 15;
+					queue.push_back(i);
+				}
+			}
+		} else {
+			for (i, block) in self.blocks.iter().enumerate() {
+				if block.emission > 0 {
+					light[i] = block.emission;
+					queue.push_back(i);
+				}
+			}
+		}
+
+		while let Some(i) = queue.pop_front() {
+			let (x, y, z) = (i % 16, i / 256, (i / 16) % 16);
+			let neighbours = [
+				(x > 0).then(|| i - 1),
+				(x < 15).then(|| i + 1),
+				(z > 0).then(|| i - 16),
+				(z < 15).then(|| i + 16),
+				(y > 0).then(|| i - 256),
+				(y + 1 < self.height()).then(|| i + 256),
+			];
+			for &neighbour in neighbours.iter().flatten() {
+				let level = light[i].saturating_sub(self.blocks[neighbour].opacity.max(1));
+				if level > light[neighbour] {
+					light[neighbour] = level;
+					queue.push_back(neighbour);
+				}
+			}
+		}
+		light
+	}
+
+	/// The packed light of the section at height y, from the light of the whole chunk
+	fn section_light(&self, levels: &[u8], y: i8) -> Vec<i8> {
+		let offset = (y as i32 - self.min_section) as usize * 4096;
+		let mut light = vec![0i8; 2048];
+		for (i, level) in levels[offset..offset + 4096].iter().enumerate() {
+			set_nibble(&mut light, i, *level);
+		}
+		light
+	}
+}
+
+/// Empties the heightmaps and light arrays that restore_derived gives back exactly, returning which ones they were
+pub fn strip_derived(chunk: &mut Value) -> StrippedDerived {
+	let mut stripped = StrippedDerived::default();
+	let version = ChunkVersion::of(chunk);
+	// The block property table is by block name
+	if !version.is_flattened() {
+		return stripped;
+	}
+	let blocks = match ChunkBlocks::new(version, chunk) {
+		Some(blocks) => blocks,
+		None => return stripped,
+	};
+
+	let bounds = version.world_bounds(chunk);
+	for (i, (key, value)) in version.heightmaps_mut(chunk).into_iter().flatten().enumerate() {
+		if let (Value::LongArray(data), Some(bounds)) = (value, bounds) {
+			if !data.is_empty() && blocks.heightmap(version, key, bounds).as_ref() == Some(data) {
+				data.clear();
+				stripped.heightmaps.push(i);
+			}
+		}
+	}
+
+	// Only computed if a section has light
+	let mut block_light = None;
+	let mut sky_light = None;
+	for (i, section) in version.sections_mut(chunk).into_iter().flatten().enumerate() {
+		let y = match section_y(section) {
+			Some(y) => y,
+			None => continue,
+		};
+		if let Value::Compound(section) = section {
+			for (key, sky) in LIGHT_KEYS.iter().zip([false, true]) {
+				if let Some(Value::ByteArray(light)) = section.get_mut(*key) {
+					if light.len() == 2048 {
+						let cache = if sky { &mut sky_light } else { &mut block_light };
+						let levels = cache.get_or_insert_with(|| blocks.light(sky));
+						if *light == blocks.section_light(levels, y) {
+							light.clear();
+							stripped.light.push((i, *key));
+						}
+					}
+				}
+			}
+		}
+	}
+	stripped
+}
+
+/// Recomputes the heightmaps and light emptied by strip_derived once the chunk's block states are restored
+pub fn restore_derived(chunk: &mut Value, stripped: &StrippedDerived) {
+	let version = ChunkVersion::of(chunk);
+	if !version.is_flattened() {
+		return;
+	}
+	let blocks = match ChunkBlocks::new(version, chunk) {
+		Some(blocks) => blocks,
+		None => return,
+	};
+
+	let bounds = version.world_bounds(chunk);
+	for (i, (key, value)) in version.heightmaps_mut(chunk).into_iter().flatten().enumerate() {
+		if let (Value::LongArray(data), Some(bounds), true) = (value, bounds, stripped.heightmaps.contains(&i)) {
+			if let Some(heightmap) = blocks.heightmap(version, key, bounds) {
+				*data = heightmap;
+			}
+		}
+	}
+
+	// Only computed if a section needs it
+	let mut block_light = None;
+	let mut sky_light = None;
+	for (i, section) in version.sections_mut(chunk).into_iter().flatten().enumerate() {
+		let y = match section_y(section) {
+			Some(y) => y,
+			None => continue,
+		};
+		if let Value::Compound(section) = section {
+			for (key, sky) in LIGHT_KEYS.iter().zip([false, true]) {
+				if let (Some(Value::ByteArray(light)), true) = (section.get_mut(*key), stripped.light.contains(&(i, *key))) {
+					let cache = if sky { &mut sky_light } else { &mut block_light };
+					let levels = cache.get_or_insert_with(|| blocks.light(sky));
+					*light = blocks.section_light(levels, y);
+				}
+			}
+		}
+	}
+}
@@ -1,15 +1,47 @@
-use std::io::Cursor;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Cursor};
+use std::marker::PhantomData;
 
+use anyhow::{anyhow, bail, Context};
 use arcode::bitbit::BitWriter;
 use arcode::decode::decoder::ArithmeticDecoder;
 use arcode::encode::encoder::ArithmeticEncoder;
 use arcode::util::source_model::SourceModel;
 use arcode::util::source_model_builder::{EOFKind, SourceModelBuilder};
+use arrayvec::ArrayVec;
 use bitbit::{BitReader, MSB};
+use byteorder::{BigEndian, ReadBytesExt};
 
+use crate::context::SectionContext;
+use crate::util::{read_varint, write_varint, Dimensions};
+
+/// Coders work on arrays of any dimensions, ordered like block states; decoding fills the whole destination
 pub trait IntegerCoder {
-	fn encode(data: &[u32; 4096], dest: &mut Vec<u8>, palette_size: u32);
-	fn decode(data: &[u8], dest: &mut[u32; 4096], palette_size: u32);
+	fn encode(data: &[u32], dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32);
+	/// Fails on data that wasn't produced by encode, rather than panicking, as it's read from archives
+	fn decode(data: &[u8], dest: &mut [u32], dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()>;
+
+	/// Encodes with the neighbouring sections available as context; coders that can't use it ignore it
+	fn encode_with_context(
+		data: &[u32],
+		dimensions: Dimensions,
+		_context: &SectionContext,
+		dest: &mut Vec<u8>,
+		palette_size: u32,
+	) {
+		Self::encode(data, dimensions, dest, palette_size)
+	}
+
+	fn decode_with_context(
+		data: &[u8],
+		_context: &SectionContext,
+		dest: &mut [u32],
+		dimensions: Dimensions,
+		palette_size: u32,
+	) -> anyhow::Result<()> {
+		Self::decode(data, dest, dimensions, palette_size)
+	}
 }
 
 pub struct ArithmeticCoding;
@@ -19,10 +51,10 @@ fn build_model(palette_size: u32) -> SourceModel {
 }
 
 impl IntegerCoder for ArithmeticCoding {
-    fn encode(data: &[u32; 4096], dest: &mut Vec<u8>, palette_size: u32) {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
 		let mut model = build_model(palette_size);
-		
-		let mut compressed_writer = BitWriter::new(Cursor::new(dest));
+
+		let mut compressed_writer = BitWriter::new(dest);
 		let mut encoder = ArithmeticEncoder::new(32);
 
 		for &sym in data {
@@ -33,38 +65,739 @@ impl IntegerCoder for ArithmeticCoding {
 		//encoder.encode(model.eof(), &model, &mut compressed_writer).unwrap();
 		encoder.finish_encode(&mut compressed_writer).unwrap();
 		compressed_writer.pad_to_byte().unwrap();
-    }
+	}
 
-    fn decode(data: &[u8], dest: &mut[u32; 4096], palette_size: u32) {
-        let mut model = build_model(palette_size);
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		let mut model = build_model(palette_size);
 
 		let mut compressed_reader = BitReader::<_, MSB>::new(Cursor::new(data));
 		let mut decoder = ArithmeticDecoder::new(32);
 
-		for i in 0..4096 {
-			let sym = decoder.decode(&model, &mut compressed_reader).unwrap();
+		for v in dest.iter_mut() {
+			let sym = decoder.decode(&model, &mut compressed_reader)?;
 			model.update_symbol(sym);
-			dest[i] = sym;
+			*v = sym;
+		}
+		Ok(())
+	}
+}
+
+/// Arithmetic coding with contexts from the already coded neighbours in the section (west, north and below in YZX order),
+/// taken from the neighbouring sections on the section's edges when they are available.
+/// Each value is first coded as a match against one of the distinct neighbour values, using a model selected by
+/// which neighbours exist and which of them are equal; values matching no neighbour are escaped and coded as a literal.
+/// Contexts only line up with 3D neighbours when the values are still in YZX order,
+/// so pair this with non-reordering transformers.
+/// Literals start from the context's prior counts, like PriorArithmeticCoding.
+pub struct NeighbourContextArithmeticCoding;
+
+/// Up to 3 distinct neighbour matches plus the escape symbol
+const NEIGHBOUR_SYMBOLS: u32 = 4;
+const NEIGHBOUR_ESCAPE: u32 = NEIGHBOUR_SYMBOLS - 1;
+
+/// The distinct values of the west, north and below neighbours of index i, along with the context they form
+fn neighbour_context(
+	data: &[u32],
+	dimensions: Dimensions,
+	section_context: &SectionContext,
+	i: usize,
+	palette_size: u32,
+) -> (ArrayVec<u32, 3>, usize) {
+	let (x, y, z) = dimensions.coords(i);
+	let row = dimensions.x;
+	let layer = dimensions.x * dimensions.z;
+	// Neighbours outside the array are on the opposite edge of the neighbouring section, if it has the same dimensions
+	let neighbour = |array: &Option<Vec<u32>>, offset: usize| {
+		array
+			.as_ref()
+			.filter(|array| array.len() == data.len())
+			.map(|array| array[i + offset])
+	};
+	let west = if x > 0 {
+		Some(data[i - 1])
+	} else {
+		neighbour(&section_context.west, row - 1)
+	};
+	let north = if z > 0 {
+		Some(data[i - row])
+	} else {
+		neighbour(&section_context.north, layer - row)
+	};
+	let below = if y > 0 {
+		Some(data[i - layer])
+	} else {
+		neighbour(&section_context.below, data.len() - layer)
+	};
+	// Blocks missing from this section's palette can't be matched
+	let [west, north, below] = [west, north, below].map(|v| v.filter(|v| *v < palette_size));
+
+	let mut candidates = ArrayVec::new();
+	for value in [west, north, below].iter().flatten().copied() {
+		if !candidates.contains(&value) {
+			candidates.push(value);
 		}
-    }
+	}
+
+	let context = west.is_some() as usize
+		| (north.is_some() as usize) << 1
+		| (below.is_some() as usize) << 2
+		| ((west.is_some() && west == north) as usize) << 3
+		| ((west.is_some() && west == below) as usize) << 4
+		| ((north.is_some() && north == below) as usize) << 5;
+	(candidates, context)
 }
 
-struct PackedIntegers; // TODO
+impl IntegerCoder for NeighbourContextArithmeticCoding {
+	fn encode(data: &[u32], dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		Self::encode_with_context(data, dimensions, &SectionContext::default(), dest, palette_size)
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		Self::decode_with_context(data, &SectionContext::default(), dest, dimensions, palette_size)
+	}
+
+	fn encode_with_context(
+		data: &[u32],
+		dimensions: Dimensions,
+		section_context: &SectionContext,
+		dest: &mut Vec<u8>,
+		palette_size: u32,
+	) {
+		let mut match_models: Vec<SourceModel> = (0..64).map(|_| build_model(NEIGHBOUR_SYMBOLS)).collect();
+		let mut literal_model = build_prior_model(section_context, palette_size);
+
+		let mut compressed_writer = BitWriter::new(dest);
+		let mut encoder = ArithmeticEncoder::new(32);
+
+		for (i, &value) in data.iter().enumerate() {
+			let (candidates, context) = neighbour_context(data, dimensions, section_context, i, palette_size);
+			let sym = candidates
+				.iter()
+				.position(|candidate| *candidate == value)
+				.map_or(NEIGHBOUR_ESCAPE, |pos| pos as u32);
+			encoder.encode(sym, &match_models[context], &mut compressed_writer).unwrap();
+			match_models[context].update_symbol(sym);
+
+			if sym == NEIGHBOUR_ESCAPE {
+				encoder.encode(value, &literal_model, &mut compressed_writer).unwrap();
+				literal_model.update_symbol(value);
+			}
+		}
+
+		encoder.finish_encode(&mut compressed_writer).unwrap();
+		compressed_writer.pad_to_byte().unwrap();
+	}
+
+	fn decode_with_context(
+		data: &[u8],
+		section_context: &SectionContext,
+		dest: &mut [u32],
+		dimensions: Dimensions,
+		palette_size: u32,
+	) -> anyhow::Result<()> {
+		let mut match_models: Vec<SourceModel> = (0..64).map(|_| build_model(NEIGHBOUR_SYMBOLS)).collect();
+		let mut literal_model = build_prior_model(section_context, palette_size);
+
+		let mut compressed_reader = BitReader::<_, MSB>::new(Cursor::new(data));
+		let mut decoder = ArithmeticDecoder::new(32);
+
+		for i in 0..dest.len() {
+			let (candidates, context) = neighbour_context(dest, dimensions, section_context, i, palette_size);
+			let sym = decoder.decode(&match_models[context], &mut compressed_reader)?;
+			match_models[context].update_symbol(sym);
+
+			dest[i] = if sym == NEIGHBOUR_ESCAPE {
+				let value = decoder.decode(&literal_model, &mut compressed_reader)?;
+				literal_model.update_symbol(value);
+				value
+			} else {
+				*candidates.get(sym as usize).context("Match against a missing neighbour")?
+			};
+		}
+		Ok(())
+	}
+}
+
+/// Arithmetic coding starting from the context's prior counts, learned by `miniworld train-priors`, rather than a uniform model.
+/// encode_values renumbers the palette by the priors first, so the counts line up with transformers that keep the palette order
+/// and with MoveToFront, whose front starts with the likeliest entries.
+/// Without prior counts for the whole palette, as for a section's changes, it behaves like ArithmeticCoding.
+pub struct PriorArithmeticCoding;
+
+fn build_prior_model(context: &SectionContext, palette_size: u32) -> SourceModel {
+	match &context.prior_counts {
+		Some(counts) if counts.len() as u32 == palette_size => {
+			SourceModelBuilder::new().counts(counts.clone()).eof(EOFKind::None).build()
+		}
+		_ => build_model(palette_size),
+	}
+}
+
+impl IntegerCoder for PriorArithmeticCoding {
+	fn encode(data: &[u32], dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		ArithmeticCoding::encode(data, dimensions, dest, palette_size)
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		ArithmeticCoding::decode(data, dest, dimensions, palette_size)
+	}
+
+	fn encode_with_context(
+		data: &[u32],
+		_dimensions: Dimensions,
+		context: &SectionContext,
+		dest: &mut Vec<u8>,
+		palette_size: u32,
+	) {
+		let mut model = build_prior_model(context, palette_size);
+
+		let mut compressed_writer = BitWriter::new(dest);
+		let mut encoder = ArithmeticEncoder::new(32);
 
-struct Simple16; // TODO
+		for &sym in data {
+			encoder.encode(sym, &model, &mut compressed_writer).unwrap();
+			model.update_symbol(sym);
+		}
+
+		encoder.finish_encode(&mut compressed_writer).unwrap();
+		compressed_writer.pad_to_byte().unwrap();
+	}
+
+	fn decode_with_context(
+		data: &[u8],
+		context: &SectionContext,
+		dest: &mut [u32],
+		_dimensions: Dimensions,
+		palette_size: u32,
+	) -> anyhow::Result<()> {
+		let mut model = build_prior_model(context, palette_size);
+
+		let mut compressed_reader = BitReader::<_, MSB>::new(Cursor::new(data));
+		let mut decoder = ArithmeticDecoder::new(32);
+
+		for v in dest.iter_mut() {
+			let sym = decoder.decode(&model, &mut compressed_reader)?;
+			model.update_symbol(sym);
+			*v = sym;
+		}
+		Ok(())
+	}
+}
+
+/// Codes each section on its own, ignoring the neighbouring sections, to measure how much a coder gains from them
+pub struct NoContext<Coder>(PhantomData<Coder>);
+
+impl<Coder: IntegerCoder> IntegerCoder for NoContext<Coder> {
+	fn encode(data: &[u32], dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		Coder::encode(data, dimensions, dest, palette_size)
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		Coder::decode(data, dest, dimensions, palette_size)
+	}
+}
+
+/// Arithmetic coding of runs of equal values, for sections that are mostly a single block.
+/// Run values and run lengths use separate adaptive models; a length is coded as its power of two bucket,
+/// followed by the remaining low bits with a uniform model. Pair with HilbertCurve for longer runs.
+pub struct RunLengthArithmeticCoding;
+
+fn run_length_bucket(length: u32) -> u32 {
+	31 - length.leading_zeros()
+}
+
+/// The uniform models for the low bits of the run lengths in each bucket, which never change so are shared by every run
+fn low_bits_models(max_bucket: u32) -> Vec<SourceModel> {
+	(0..=max_bucket).map(|bucket| build_model(1 << bucket)).collect()
+}
+
+impl IntegerCoder for RunLengthArithmeticCoding {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		let mut value_model = build_model(palette_size);
+		// Run lengths are 1 to the length of the array
+		let mut bucket_model = build_model(run_length_bucket(data.len() as u32) + 1);
+		let low_bits_models = low_bits_models(run_length_bucket(data.len() as u32));
+
+		let mut compressed_writer = BitWriter::new(dest);
+		let mut encoder = ArithmeticEncoder::new(32);
+
+		let mut i = 0;
+		while i < data.len() {
+			let value = data[i];
+			let length = data[i..].iter().take_while(|v| **v == value).count() as u32;
+			i += length as usize;
+
+			encoder.encode(value, &value_model, &mut compressed_writer).unwrap();
+			value_model.update_symbol(value);
+
+			let bucket = run_length_bucket(length);
+			encoder.encode(bucket, &bucket_model, &mut compressed_writer).unwrap();
+			bucket_model.update_symbol(bucket);
+			if bucket > 0 {
+				encoder
+					.encode(
+						length - (1 << bucket),
+						&low_bits_models[bucket as usize],
+						&mut compressed_writer,
+					)
+					.unwrap();
+			}
+		}
+
+		encoder.finish_encode(&mut compressed_writer).unwrap();
+		compressed_writer.pad_to_byte().unwrap();
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		let mut value_model = build_model(palette_size);
+		let mut bucket_model = build_model(run_length_bucket(dest.len() as u32) + 1);
+		let low_bits_models = low_bits_models(run_length_bucket(dest.len() as u32));
+
+		let mut compressed_reader = BitReader::<_, MSB>::new(Cursor::new(data));
+		let mut decoder = ArithmeticDecoder::new(32);
 
+		let mut i = 0;
+		while i < dest.len() {
+			let value = decoder.decode(&value_model, &mut compressed_reader)?;
+			value_model.update_symbol(value);
+
+			let bucket = decoder.decode(&bucket_model, &mut compressed_reader)?;
+			bucket_model.update_symbol(bucket);
+			let mut length = 1 << bucket;
+			if bucket > 0 {
+				length += decoder.decode(&low_bits_models[bucket as usize], &mut compressed_reader)?;
+			}
+
+			dest.get_mut(i..i + length as usize)
+				.context("Run goes past the end of the array")?
+				.fill(value);
+			i += length as usize;
+		}
+		Ok(())
+	}
+}
+
+/// Packs each value into the minimum number of bits needed to represent the palette
+pub struct PackedIntegers;
+
+fn min_bits(palette_size: u32) -> usize {
+	(32 - palette_size.saturating_sub(1).leading_zeros()).max(1) as usize
+}
+
+impl IntegerCoder for PackedIntegers {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		let num_bits = min_bits(palette_size);
+		let mut writer = BitWriter::new(dest);
+
+		for &v in data {
+			writer.write_bits(v, num_bits).unwrap();
+		}
+
+		writer.pad_to_byte().unwrap();
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		let num_bits = min_bits(palette_size);
+		let mut reader = BitReader::<_, MSB>::new(Cursor::new(data));
+
+		for v in dest.iter_mut() {
+			*v = reader.read_bits(num_bits)?;
+		}
+		Ok(())
+	}
+}
+
+/// Packs runs of values into 32-bit words using the Simple16 scheme, which favours streams of small values
+pub struct Simple16;
+
+impl IntegerCoder for Simple16 {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, _palette_size: u32) {
+		simple_16::compress(data, dest).unwrap();
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, _palette_size: u32) -> anyhow::Result<()> {
+		let mut values = Vec::with_capacity(dest.len());
+		simple_16::decompress(data, &mut values).map_err(|_| anyhow!("Invalid Simple16 word"))?;
+		// The last word can be padded with extra zeroes
+		dest.copy_from_slice(values.get(..dest.len()).context("Too few Simple16 values")?);
+		Ok(())
+	}
+}
+
+/// Static range asymmetric numeral systems with byte-wise renormalisation.
+/// The symbol counts are scaled to frequencies summing to a power of two, which for arrays with a power of two length
+/// up to 1 << RANS_MAX_SCALE_BITS are the counts themselves; they are transmitted as varints ahead of the coded data.
+pub struct Rans;
+
+/// Lower bound of the normalised state interval
+const RANS_L: u32 = 1 << 23;
+/// Frequencies sum to at most 1 << RANS_MAX_SCALE_BITS, unless an array has more distinct values than that
+const RANS_MAX_SCALE_BITS: u32 = 16;
+
+fn ceil_log2(n: usize) -> u32 {
+	usize::BITS - n.saturating_sub(1).leading_zeros()
+}
+
+/// The frequency of each symbol, summing to 1 << the returned scale bits, with every symbol in the data getting at least 1
+fn rans_frequencies(data: &[u32], palette_size: u32) -> (u32, Vec<u32>) {
+	let mut counts = vec![0u64; palette_size as usize];
+	for &v in data {
+		counts[v as usize] += 1;
+	}
+	let used = counts.iter().filter(|count| **count > 0).count();
+	let scale_bits = ceil_log2(data.len()).min(RANS_MAX_SCALE_BITS).max(ceil_log2(used));
+	let total = 1u64 << scale_bits;
+	let mut freqs: Vec<u32> = counts
+		.iter()
+		.map(|&count| {
+			if count == 0 {
+				0
+			} else {
+				(count * total / data.len() as u64).max(1) as u32
+			}
+		})
+		.collect();
+
+	// Rounding leaves the sum a little off, so the most common symbols make up the difference
+	let mut by_freq: Vec<usize> = (0..freqs.len()).collect();
+	by_freq.sort_by_key(|s| Reverse(freqs[*s]));
+	let sum: u64 = freqs.iter().map(|freq| *freq as u64).sum();
+	if sum < total {
+		freqs[by_freq[0]] += (total - sum) as u32;
+	} else {
+		let mut excess = (sum - total) as u32;
+		for s in by_freq {
+			let taken = excess.min(freqs[s].saturating_sub(1));
+			freqs[s] -= taken;
+			excess -= taken;
+		}
+	}
+	(scale_bits, freqs)
+}
+
+impl IntegerCoder for Rans {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		let (scale_bits, freqs) = rans_frequencies(data, palette_size);
+		let mut starts = vec![0u32; palette_size as usize];
+		for s in 1..freqs.len() {
+			starts[s] = starts[s - 1] + freqs[s - 1];
+		}
+		for &freq in &freqs {
+			write_varint(dest, freq as u64).unwrap();
+		}
+
+		// rANS works as a stack, so encode backwards and reverse the output for the decoder to read forwards
+		let mut out = vec![];
+		let mut x = RANS_L;
+		for &v in data.iter().rev() {
+			let freq = freqs[v as usize];
+			let x_max = ((RANS_L >> scale_bits) << 8) * freq;
+			while x >= x_max {
+				out.push(x as u8);
+				x >>= 8;
+			}
+			x = ((x / freq) << scale_bits) + (x % freq) + starts[v as usize];
+		}
+		out.extend_from_slice(&x.to_le_bytes());
+		dest.extend(out.iter().rev());
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		let mut reader = Cursor::new(data);
+		let freqs: Vec<u64> = (0..palette_size)
+			.map(|_| read_varint(&mut reader))
+			.collect::<io::Result<_>>()?;
+		let total = freqs
+			.iter()
+			.try_fold(0u64, |sum, freq| sum.checked_add(*freq))
+			.context("rANS frequencies are too large")?;
+		// The state interval has room for up to 1 << 23 slots
+		if !total.is_power_of_two() || total > RANS_L as u64 {
+			bail!("rANS frequencies don't add up to a power of two");
+		}
+		let scale_bits = total.trailing_zeros();
+		let freqs: Vec<u32> = freqs.into_iter().map(|freq| freq as u32).collect();
+		let mut starts = vec![];
+		// Maps each slot of the cumulative frequency range back to its symbol
+		let mut symbols = vec![0u32; total as usize];
+		let mut start = 0;
+		for (s, &freq) in freqs.iter().enumerate() {
+			symbols[start as usize..(start + freq) as usize].fill(s as u32);
+			starts.push(start);
+			start += freq;
+		}
+
+		let mut x = reader.read_u32::<BigEndian>()?;
+		for v in dest.iter_mut() {
+			let slot = x & ((1 << scale_bits) - 1);
+			let s = symbols[slot as usize];
+			*v = s;
+			x = freqs[s as usize] * (x >> scale_bits) + slot - starts[s as usize];
+			while x < RANS_L {
+				x = (x << 8) | reader.read_u8()? as u32;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Canonical Huffman coding built from each section's symbol frequencies.
+/// The code lengths are transmitted in HUFFMAN_LENGTH_BITS bits per palette entry, from which the decoder
+/// rebuilds the same canonical code and decodes with a lookup table indexed by the next max length bits.
+pub struct Huffman;
+
+const HUFFMAN_LENGTH_BITS: usize = 5;
+/// Longer codes than any array this small can need, which would only come from corrupt data
+const HUFFMAN_MAX_LENGTH: usize = 24;
+
+/// Computes Huffman code lengths for each symbol, zero for unused symbols
+fn huffman_code_lengths(freqs: &[u32]) -> Vec<u8> {
+	let mut lengths = vec![0u8; freqs.len()];
+	// Every node keeps the symbols below it, so merging two nodes makes all of their codes one bit longer
+	let mut heap: BinaryHeap<Reverse<(u32, Vec<usize>)>> = freqs
+		.iter()
+		.enumerate()
+		.filter(|(_, freq)| **freq > 0)
+		.map(|(s, freq)| Reverse((*freq, vec![s])))
+		.collect();
+	if heap.len() == 1 {
+		// A lone symbol still needs one bit per value
+		let Reverse((_, symbols)) = heap.pop().unwrap();
+		lengths[symbols[0]] = 1;
+		return lengths;
+	}
+	while heap.len() > 1 {
+		let Reverse((freq_a, mut symbols_a)) = heap.pop().unwrap();
+		let Reverse((freq_b, symbols_b)) = heap.pop().unwrap();
+		symbols_a.extend(symbols_b);
+		for &s in &symbols_a {
+			lengths[s] += 1;
+		}
+		heap.push(Reverse((freq_a + freq_b, symbols_a)));
+	}
+	lengths
+}
+
+/// Assigns canonical codes: shorter codes first, ties broken by symbol
+fn huffman_canonical_codes(lengths: &[u8]) -> Vec<u32> {
+	let mut symbols: Vec<usize> = (0..lengths.len()).filter(|s| lengths[*s] > 0).collect();
+	symbols.sort_by_key(|s| (lengths[*s], *s));
+	let mut codes = vec![0u32; lengths.len()];
+	let mut code = 0u32;
+	let mut prev_length = 0;
+	for s in symbols {
+		code <<= lengths[s] - prev_length;
+		codes[s] = code;
+		code += 1;
+		prev_length = lengths[s];
+	}
+	codes
+}
+
+impl IntegerCoder for Huffman {
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, palette_size: u32) {
+		let mut freqs = vec![0u32; palette_size as usize];
+		for &v in data {
+			freqs[v as usize] += 1;
+		}
+		let lengths = huffman_code_lengths(&freqs);
+		let codes = huffman_canonical_codes(&lengths);
+
+		let mut writer = BitWriter::new(dest);
+		for &length in &lengths {
+			writer.write_bits(length as u32, HUFFMAN_LENGTH_BITS).unwrap();
+		}
+		for &v in data {
+			writer.write_bits(codes[v as usize], lengths[v as usize] as usize).unwrap();
+		}
+		writer.pad_to_byte().unwrap();
+	}
+
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, palette_size: u32) -> anyhow::Result<()> {
+		let mut reader = BitReader::<_, MSB>::new(Cursor::new(data));
+		let lengths: Vec<u8> = (0..palette_size)
+			.map(|_| Ok(reader.read_bits(HUFFMAN_LENGTH_BITS)? as u8))
+			.collect::<io::Result<_>>()?;
+		let codes = huffman_canonical_codes(&lengths);
+
+		// Every index starting with a symbol's code maps to that symbol and its length
+		let max_length = lengths.iter().copied().max().unwrap_or(0) as usize;
+		if max_length > HUFFMAN_MAX_LENGTH {
+			bail!("Huffman code length {} is too long", max_length);
+		}
+		// The codes have to fit in the table without overlapping
+		if lengths
+			.iter()
+			.filter(|length| **length > 0)
+			.map(|length| 1u64 << (max_length - *length as usize))
+			.sum::<u64>()
+			> 1 << max_length
+		{
+			bail!("Huffman code lengths don't form a prefix code");
+		}
+		let mut table = vec![(0u32, 0u8); 1 << max_length];
+		for (s, &length) in lengths.iter().enumerate().filter(|(_, length)| **length > 0) {
+			let start = (codes[s] as usize) << (max_length - length as usize);
+			let end = (codes[s] as usize + 1) << (max_length - length as usize);
+			table[start..end].fill((s as u32, length));
+		}
+
+		// The lengths header takes whole bits rather than bytes, so keep track of the bit position
+		let mut position = palette_size as usize * HUFFMAN_LENGTH_BITS;
+		for v in dest.iter_mut() {
+			let mut index = 0usize;
+			for bit in position..position + max_length {
+				let byte = data.get(bit / 8).copied().unwrap_or(0);
+				index = (index << 1) | ((byte >> (7 - bit % 8)) & 1) as usize;
+			}
+			let (symbol, length) = table[index];
+			*v = symbol;
+			position += length as usize;
+		}
+		Ok(())
+	}
+}
+
+/// Stores each value in a single byte. Values from BYTEWISE_ESCAPE up, from palettes over 255 entries,
+/// are stored as the escape byte followed by a varint of the rest of the value.
 pub struct Bytewise;
 
+const BYTEWISE_ESCAPE: u32 = 255;
+
 impl IntegerCoder for Bytewise {
-    fn encode(data: &[u32; 4096], dest: &mut Vec<u8>, _palette_size: u32) {
-        for v in data {
-			dest.push(*v as u8);
+	fn encode(data: &[u32], _dimensions: Dimensions, dest: &mut Vec<u8>, _palette_size: u32) {
+		for &v in data {
+			if v < BYTEWISE_ESCAPE {
+				dest.push(v as u8);
+			} else {
+				dest.push(BYTEWISE_ESCAPE as u8);
+				write_varint(dest, (v - BYTEWISE_ESCAPE) as u64).unwrap();
+			}
 		}
-    }
+	}
 
-    fn decode(data: &[u8], dest: &mut[u32; 4096], _palette_size: u32) {
-        for i in 0..4096 {
-			dest[i] = data[i] as u32;
+	fn decode(data: &[u8], dest: &mut [u32], _dimensions: Dimensions, _palette_size: u32) -> anyhow::Result<()> {
+		let mut reader = Cursor::new(data);
+		for v in dest.iter_mut() {
+			let byte = reader.read_u8()? as u32;
+			*v = if byte < BYTEWISE_ESCAPE {
+				byte
+			} else {
+				BYTEWISE_ESCAPE + read_varint(&mut reader)? as u32
+			};
 		}
-    }
-}
\ No newline at end of file
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::context::NO_PALETTE_ENTRY;
+
+	/// A section's worth of values where low values are much more common, like block state indices
+	fn skewed_values(palette_size: u32) -> Vec<u32> {
+		let mut state = 0x2545_f491u32;
+		(0..4096)
+			.map(|_| {
+				state ^= state << 13;
+				state ^= state >> 17;
+				state ^= state << 5;
+				(state % palette_size).min(state % 7)
+			})
+			.collect()
+	}
+
+	fn round_trip<Coder: IntegerCoder>(data: &[u32], palette_size: u32) {
+		let mut encoded = vec![];
+		Coder::encode(data, Dimensions::SECTION, &mut encoded, palette_size);
+		let mut decoded = vec![0u32; data.len()];
+		Coder::decode(&encoded, &mut decoded, Dimensions::SECTION, palette_size).unwrap();
+		assert_eq!(data, &decoded[..]);
+	}
+
+	fn round_trips<Coder: IntegerCoder>() {
+		round_trip::<Coder>(&skewed_values(2), 2);
+		round_trip::<Coder>(&skewed_values(40), 40);
+		round_trip::<Coder>(&[3; 4096], 4);
+		// Every value once, with a palette over 255 entries
+		round_trip::<Coder>(&(0..4096).collect::<Vec<u32>>(), 4096);
+	}
+
+	#[test]
+	fn rans_round_trips() {
+		round_trips::<Rans>();
+	}
+
+	#[test]
+	fn huffman_round_trips() {
+		round_trips::<Huffman>();
+	}
+
+	#[test]
+	fn simple16_round_trips() {
+		round_trips::<Simple16>();
+	}
+
+	#[test]
+	fn bytewise_round_trips() {
+		round_trips::<Bytewise>();
+	}
+
+	#[test]
+	fn neighbour_context_round_trips() {
+		round_trips::<NeighbourContextArithmeticCoding>();
+
+		let data = skewed_values(40);
+		// Neighbouring sections with blocks missing from the palette, one of them the wrong size to line up
+		let mut below = data.clone();
+		below.iter_mut().step_by(9).for_each(|v| *v = NO_PALETTE_ENTRY);
+		let context = SectionContext {
+			below: Some(below),
+			west: Some(data.iter().rev().copied().collect()),
+			north: Some(vec![0; 16]),
+			prior_counts: Some((1..=40).rev().collect()),
+			..SectionContext::default()
+		};
+		let mut encoded = vec![];
+		NeighbourContextArithmeticCoding::encode_with_context(&data, Dimensions::SECTION, &context, &mut encoded, 40);
+		let mut decoded = vec![0u32; data.len()];
+		NeighbourContextArithmeticCoding::decode_with_context(&encoded, &context, &mut decoded, Dimensions::SECTION, 40).unwrap();
+		assert_eq!(data, decoded);
+
+		// Decoding against different neighbours gives different values
+		NeighbourContextArithmeticCoding::decode_with_context(
+			&encoded,
+			&SectionContext::default(),
+			&mut decoded,
+			Dimensions::SECTION,
+			40,
+		)
+		.ok();
+		assert_ne!(data, decoded);
+	}
+
+	#[test]
+	fn run_length_round_trips() {
+		round_trips::<RunLengthArithmeticCoding>();
+		// A run in every length bucket
+		let data: Vec<u32> = (0..12).flat_map(|bucket| vec![bucket % 3; 1 << bucket]).chain([3]).collect();
+		round_trip::<RunLengthArithmeticCoding>(&data, 4);
+
+		// Runs past the end of the array are an error
+		let mut encoded = vec![];
+		RunLengthArithmeticCoding::encode(&[1; 3000], Dimensions::SECTION, &mut encoded, 2);
+		let mut decoded = vec![0u32; 2500];
+		assert!(RunLengthArithmeticCoding::decode(&encoded, &mut decoded, Dimensions::SECTION, 2).is_err());
+	}
+
+	#[test]
+	fn truncated_data_is_an_error() {
+		let data = skewed_values(40);
+		let mut encoded = vec![];
+		Rans::encode(&data, Dimensions::SECTION, &mut encoded, 40);
+		encoded.truncate(encoded.len() / 2);
+		let mut decoded = vec![0u32; data.len()];
+		assert!(Rans::decode(&encoded, &mut decoded, Dimensions::SECTION, 40).is_err());
+	}
+}
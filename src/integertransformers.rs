@@ -1,18 +1,30 @@
-use core::num;
-use std::{convert::TryInto, marker::PhantomData};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex, OnceLock};
 
+use anyhow::Context;
 use fixed_vec_deque::FixedVecDeque;
-use hilbert_index::{FromHilbertIndex, ToHilbertIndex};
+use hilbert_index::ToHilbertIndex;
 
+use crate::util::{write_varint, Dimensions};
+
+/// Transformers work on arrays of any dimensions, ordered like block states.
+/// Side information is a stack: transform pushes any bytes reverse needs, and reverse pops them off again.
+/// This lets combined transformers share one buffer, as they are reversed in the opposite order.
+/// Reversing fails on side information that transform didn't produce, as it's read from archives;
+/// the values are checked against the palette size beforehand.
 pub trait IntegerTransformer {
-	fn transform(data: &mut[u32; 4096], palette_size: &mut u32);
-	fn reverse(data: &mut[u32; 4096], palette_size: &mut u32);
+	fn transform(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>);
+	fn reverse(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) -> anyhow::Result<()>;
 }
 
+/// Only used by the benchmarks that are commented out
+#[allow(dead_code)]
 pub struct DeltaLeft;
 
 impl IntegerTransformer for DeltaLeft {
-    fn transform(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn transform(data: &mut [u32], _dimensions: Dimensions, palette_size: &mut u32, _side_info: &mut Vec<u8>) {
 		let num_bits = match (*palette_size as f64).log2().ceil() as usize {
 			0..=4 => 4,
 			x => x,
@@ -20,7 +32,7 @@ impl IntegerTransformer for DeltaLeft {
 		let mask = (1 << num_bits) - 1;
 
 		let mut prev = 0u32;
-        for v in data {
+		for v in data {
 			let vcopy = *v;
 			*v = (vcopy.wrapping_sub(prev)) & mask;
 			prev = vcopy;
@@ -28,33 +40,121 @@ impl IntegerTransformer for DeltaLeft {
 
 		// Increase palette size to full num_bits range
 		*palette_size = 1 << num_bits;
-    }
+	}
 
-    fn reverse(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn reverse(
+		data: &mut [u32],
+		_dimensions: Dimensions,
+		palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
 		let num_bits = match (*palette_size as f64).log2().ceil() as usize {
 			0..=4 => 4,
 			x => x,
 		};
 		let mask = (1 << num_bits) - 1;
 
-        let mut prev = 0u32;
-        for v in data {
-			let delta = *v;
-			*v = (prev.wrapping_add(prev)) & mask;
+		let mut prev = 0u32;
+		for v in data {
+			*v = (prev.wrapping_add(*v)) & mask;
 			prev = *v;
 		}
 
-		// Increase palette size to full num_bits range
+		// The original palette size is lost, so keep the full num_bits range
 		*palette_size = 1 << num_bits;
-    }
+		Ok(())
+	}
+}
+
+/// Replaces each value with its difference from the value above it (+Y), modulo the palette size, leaving the top layer as is.
+/// Suits light, which mostly stays the same or falls off slowly going down.
+pub struct DeltaAbove;
+
+impl IntegerTransformer for DeltaAbove {
+	fn transform(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, _side_info: &mut Vec<u8>) {
+		let layer = dimensions.x * dimensions.z;
+		// Bottom up, so the values above are still the original ones
+		for i in 0..data.len() - layer {
+			data[i] = (data[i] + *palette_size - data[i + layer]) % *palette_size;
+		}
+	}
+
+	fn reverse(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, _side_info: &mut Vec<u8>) -> anyhow::Result<()> {
+		let layer = dimensions.x * dimensions.z;
+		// Top down, so the values above are already restored
+		for i in (0..data.len() - layer).rev() {
+			data[i] = (data[i] + data[i + layer]) % *palette_size;
+		}
+		Ok(())
+	}
+}
+
+/// Renumbers the palette so the most common value gets index 0, the next most common index 1 and so on.
+/// The original index of each new palette entry is stored in the side information to restore the palette order.
+pub struct FrequencySortedPalette;
+
+impl IntegerTransformer for FrequencySortedPalette {
+	fn transform(data: &mut [u32], _dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) {
+		let mut freqs = vec![0u32; *palette_size as usize];
+		for v in data.iter() {
+			freqs[*v as usize] += 1;
+		}
+		// Stable, so equally common entries keep their relative order
+		let mut order: Vec<u32> = (0..*palette_size).collect();
+		order.sort_by_key(|v| Reverse(freqs[*v as usize]));
+
+		let mut new_index = vec![0u32; *palette_size as usize];
+		for (i, v) in order.iter().enumerate() {
+			new_index[*v as usize] = i as u32;
+		}
+		for v in data.iter_mut() {
+			*v = new_index[*v as usize];
+		}
+
+		for v in order.iter().rev() {
+			push_varint(side_info, *v);
+		}
+	}
+
+	fn reverse(data: &mut [u32], _dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) -> anyhow::Result<()> {
+		let order: Vec<u32> = (0..*palette_size)
+			.map(|_| pop_varint(side_info))
+			.collect::<anyhow::Result<_>>()?;
+		for v in data.iter_mut() {
+			*v = order[*v as usize];
+		}
+		Ok(())
+	}
+}
+
+/// Pushes a varint onto the side information, in the order pop_varint takes its bytes back off
+fn push_varint(side_info: &mut Vec<u8>, value: u32) {
+	let mut bytes = vec![];
+	write_varint(&mut bytes, value as u64).unwrap();
+	side_info.extend(bytes.iter().rev());
+}
+
+fn pop_varint(side_info: &mut Vec<u8>) -> anyhow::Result<u32> {
+	let mut value = 0u32;
+	let mut shift = 0;
+	loop {
+		let byte = side_info.pop().context("Missing side information")?;
+		value |= ((byte & 0b0111_1111) as u32)
+			.checked_shl(shift)
+			.context("Side information varint is too long")?;
+		if byte & 0b1000_0000 == 0 {
+			return Ok(value);
+		}
+		shift += 7;
+	}
 }
 
 pub struct MoveToFront;
 
 impl IntegerTransformer for MoveToFront {
-    fn transform(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn transform(data: &mut [u32], _dimensions: Dimensions, palette_size: &mut u32, _side_info: &mut Vec<u8>) {
 		let mut statemap: Vec<u32> = (0..*palette_size).collect();
-        
+
 		for v in data {
 			let value = *v;
 			if value >= *palette_size {
@@ -62,47 +162,61 @@ impl IntegerTransformer for MoveToFront {
 			}
 			let curr_pos = statemap.iter().position(|state| *state == value).unwrap() as u32;
 			*v = curr_pos;
-			
+
 			statemap.remove(curr_pos.try_into().unwrap());
 			statemap.insert(0, value);
 		}
-    }
+	}
 
-    fn reverse(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn reverse(
+		data: &mut [u32],
+		_dimensions: Dimensions,
+		palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
 		let mut statemap: Vec<u32> = (0..*palette_size).collect();
 
-        for v in data {
+		for v in data {
 			let curr_pos = *v;
 			*v = statemap[curr_pos as usize];
-			
+
 			let value = statemap.remove(curr_pos.try_into().unwrap());
 			statemap.insert(0, value);
 		}
-    }
+		Ok(())
+	}
 }
 
 pub struct None;
 
 impl IntegerTransformer for None {
-    fn transform(_data: &mut[u32; 4096], _palette_size: &mut u32) {
-        // Do nothing!
-    }
+	fn transform(_data: &mut [u32], _dimensions: Dimensions, _palette_size: &mut u32, _side_info: &mut Vec<u8>) {
+		// Do nothing!
+	}
 
-    fn reverse(_data: &mut[u32; 4096], _palette_size: &mut u32) {
-        // Do nothing!
-    }
+	fn reverse(
+		_data: &mut [u32],
+		_dimensions: Dimensions,
+		_palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
+		// Do nothing!
+		Ok(())
+	}
 }
 
+/// Only used by the benchmarks that are commented out
+#[allow(dead_code)]
 pub struct MoveToFrontLookbehind;
 
 impl IntegerTransformer for MoveToFrontLookbehind {
-    fn transform(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn transform(data: &mut [u32], _dimensions: Dimensions, palette_size: &mut u32, _side_info: &mut Vec<u8>) {
 		let mut statemap: Vec<u32> = (0..*palette_size).collect();
 		let mut lookbehind = FixedVecDeque::<[u32; 256]>::new();
 		// Add 2 new symbols referring to the values 16 and 256 behind respectively
 		let sym_behind_16 = *palette_size;
 		let sym_behind_256 = *palette_size + 1;
-        
+
 		for v in data {
 			let value = *v;
 			if value >= *palette_size {
@@ -120,23 +234,30 @@ impl IntegerTransformer for MoveToFrontLookbehind {
 				*v = curr_pos;
 			}
 			*lookbehind.push_front() = value;
-			
+
 			statemap.remove(curr_pos.try_into().unwrap());
 			statemap.insert(0, value);
 		}
 
 		*palette_size += 2;
-    }
+	}
 
-    fn reverse(data: &mut[u32; 4096], palette_size: &mut u32) {
+	fn reverse(
+		data: &mut [u32],
+		_dimensions: Dimensions,
+		palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
+		// Remove the 2 lookbehind symbols added by transform
+		*palette_size = palette_size
+			.checked_sub(2)
+			.context("Palette is missing the lookbehind symbols")?;
 		let mut statemap: Vec<u32> = (0..*palette_size).collect();
 		let mut lookbehind = FixedVecDeque::<[u32; 256]>::new();
-		// Add 2 new symbols referring to the values 16 and 256 behind respectively
 		let sym_behind_16 = *palette_size;
 		let sym_behind_256 = *palette_size + 1;
-		*palette_size += 2;
 
-        for v in data {
+		for v in data {
 			let curr_pos = if *v == sym_behind_16 {
 				let value = lookbehind_or_zero(&lookbehind, 15);
 				statemap.iter().position(|state| *state == value).unwrap() as u32
@@ -149,155 +270,272 @@ impl IntegerTransformer for MoveToFrontLookbehind {
 
 			*v = statemap[curr_pos as usize];
 			*lookbehind.push_front() = *v;
-			
+
 			let value = statemap.remove(curr_pos.try_into().unwrap());
 			statemap.insert(0, value);
 		}
-    }
+		Ok(())
+	}
 }
 
+#[allow(dead_code)]
 fn lookbehind_or_zero(buf: &FixedVecDeque<[u32; 256]>, index: usize) -> u32 {
 	match buf.get(index) {
 		Some(v) => *v,
-		Option::None => 0
+		Option::None => 0,
 	}
 }
 
+/// Only used by the benchmarks that are commented out
+#[allow(dead_code)]
 pub struct ZOrderCurve;
 
 impl IntegerTransformer for ZOrderCurve {
-    fn transform(data: &mut[u32; 4096], _palette_size: &mut u32) {
-		let copy = data.clone();
-		for (i, v) in copy.iter().enumerate() {
-			data[interleave_idx(i as u32) as usize] = *v;
-		}
-    }
+	fn transform(data: &mut [u32], dimensions: Dimensions, _palette_size: &mut u32, _side_info: &mut Vec<u8>) {
+		reorder(data, &z_order(dimensions));
+	}
 
-    fn reverse(data: &mut[u32; 4096], _palette_size: &mut u32) {
-		let copy = data.clone();
-		for (i, v) in copy.iter().enumerate() {
-			data[uninterleave_idx(i as u32) as usize] = *v;
-		}
-    }
+	fn reverse(
+		data: &mut [u32],
+		dimensions: Dimensions,
+		_palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
+		unorder(data, &z_order(dimensions));
+		Ok(())
+	}
 }
 
-fn interleave_idx(i: u32) -> u32 {
-	// YZX ordering
-	interleave_xyz(i & 15, (i >> 8) & 15, (i >> 4) & 15)
+#[allow(dead_code)]
+fn z_order(dimensions: Dimensions) -> Arc<[usize]> {
+	static ORDERS: CurveOrders = OnceLock::new();
+	cached_order(&ORDERS, dimensions, || curve_order(dimensions, z_order_index))
 }
 
-fn interleave_xyz(x: u32, y: u32, z: u32) -> u32 {
-	interleave_4_bits(x) | (interleave_4_bits(y) << 2) | (interleave_4_bits(z) << 1)
+#[allow(dead_code)]
+fn z_order_index(x: usize, y: usize, z: usize, _level: usize) -> usize {
+	spread_bits(x) | (spread_bits(z) << 1) | (spread_bits(y) << 2)
 }
 
-fn interleave_4_bits(mut x: u32) -> u32 {
+/// Spaces out the bits of a coordinate so two others can be interleaved with them
+#[allow(dead_code)]
+fn spread_bits(mut x: usize) -> usize {
 	let mut v = 0;
-	// 3 components, 4 bits
-	for shift in (0..4*3).step_by(3) {
+	let mut shift = 0;
+	while x > 0 {
 		v |= (x & 1) << shift;
 		x >>= 1;
+		shift += 3;
 	}
 	v
 }
 
-fn uninterleave_idx(i: u32) -> u32 {
-	get_idx(uninterleave_4_bits(i), uninterleave_4_bits(i >> 2), uninterleave_4_bits(i >> 1))
+/// The indices of the values in the order a curve visits them.
+/// Values are sorted by their index along the curve, which also handles dimensions the curve can't exactly fill.
+fn curve_order(dimensions: Dimensions, curve_index: fn(usize, usize, usize, usize) -> usize) -> Vec<usize> {
+	let max_dimension = dimensions.x.max(dimensions.y).max(dimensions.z);
+	// The number of bits needed for each coordinate
+	let level = ((usize::BITS - max_dimension.saturating_sub(1).leading_zeros()) as usize).max(1);
+	let mut order: Vec<usize> = (0..dimensions.volume()).collect();
+	order.sort_by_cached_key(|i| {
+		let (x, y, z) = dimensions.coords(*i);
+		curve_index(x, y, z, level)
+	});
+	order
 }
 
-fn uninterleave_4_bits(mut x: u32) -> u32 {
-	let mut v = 0;
-	// 3 components, 4 bits
-	for shift in 0..4 {
-		v |= (x & 1) << shift;
-		x >>= 3;
+/// The curve orders already built for each shape of array, as sorting the indices costs far more than reordering the values
+type CurveOrders = OnceLock<Mutex<HashMap<Dimensions, Arc<[usize]>>>>;
+
+fn cached_order(orders: &'static CurveOrders, dimensions: Dimensions, build: impl FnOnce() -> Vec<usize>) -> Arc<[usize]> {
+	let mut orders = orders.get_or_init(Default::default).lock().unwrap();
+	orders.entry(dimensions).or_insert_with(|| build().into()).clone()
+}
+
+/// Moves values into curve order
+fn reorder(data: &mut [u32], order: &[usize]) {
+	let copy = data.to_vec();
+	for (v, i) in data.iter_mut().zip(order) {
+		*v = copy[*i];
 	}
-	v
 }
 
-fn get_idx(x: u32, y: u32, z: u32) -> u32 {
-	y << 8 | z << 4 | x
+/// Moves values from curve order back to their original positions
+fn unorder(data: &mut [u32], order: &[usize]) {
+	let copy = data.to_vec();
+	for (v, i) in copy.iter().zip(order) {
+		data[*i] = *v;
+	}
 }
 
 impl<A: IntegerTransformer, B: IntegerTransformer> IntegerTransformer for (A, B) {
-	fn transform(data: &mut[u32; 4096], palette_size: &mut u32) {
-		A::transform(data, palette_size);
-		B::transform(data, palette_size);
-    }
-
-    fn reverse(data: &mut[u32; 4096], palette_size: &mut u32) {
-		B::reverse(data, palette_size);
-		A::reverse(data, palette_size);
-    }
+	fn transform(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) {
+		A::transform(data, dimensions, palette_size, side_info);
+		B::transform(data, dimensions, palette_size, side_info);
+	}
+
+	fn reverse(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) -> anyhow::Result<()> {
+		B::reverse(data, dimensions, palette_size, side_info)?;
+		A::reverse(data, dimensions, palette_size, side_info)
+	}
 }
 
 pub struct HilbertCurve;
 
-const HILBERT_LEVEL: usize = 4;
-
-impl IntegerTransformer for HilbertCurve {
-    fn transform(data: &mut[u32; 4096], _palette_size: &mut u32) {
-		let copy = data.clone();
-		for (i, v) in copy.iter().enumerate() {
+fn hilbert_order(dimensions: Dimensions) -> Arc<[usize]> {
+	static ORDERS: CurveOrders = OnceLock::new();
+	cached_order(&ORDERS, dimensions, || {
+		if dimensions.y == 1 {
+			curve_order(dimensions, |x, _, z, level| [x, z].to_hilbert_index(level))
+		} else {
 			// X and Y swapped for better locality
-			data[[(i >> 8) & 15, i & 15, (i >> 4) & 15].to_hilbert_index(HILBERT_LEVEL)] = *v;
+			curve_order(dimensions, |x, y, z, level| [y, x, z].to_hilbert_index(level))
 		}
-    }
+	})
+}
 
-    fn reverse(data: &mut[u32; 4096], _palette_size: &mut u32) {
-		let copy = data.clone();
-		for (i, v) in copy.iter().enumerate() {
-			let [x, y, z] = i.from_hilbert_index(HILBERT_LEVEL);
-			// X and Y swapped for better locality
-			data[get_idx(y as u32, x as u32, z as u32) as usize] = *v;
-		}
-    }
+impl IntegerTransformer for HilbertCurve {
+	fn transform(data: &mut [u32], dimensions: Dimensions, _palette_size: &mut u32, _side_info: &mut Vec<u8>) {
+		reorder(data, &hilbert_order(dimensions));
+	}
+
+	fn reverse(
+		data: &mut [u32],
+		dimensions: Dimensions,
+		_palette_size: &mut u32,
+		_side_info: &mut Vec<u8>,
+	) -> anyhow::Result<()> {
+		unorder(data, &hilbert_order(dimensions));
+		Ok(())
+	}
 }
 
 pub struct HilbertCurveAdaptive;
 
 impl IntegerTransformer for HilbertCurveAdaptive {
-    fn transform(data: &mut[u32; 4096], palette_size: &mut u32) {
-		let copy = data.clone();
-		let mut run_count_no_curve = 0;
-		let mut run_count = 0;
-		let mut last_v = *palette_size + 1;
-
-		// For each symbol where the value is the same as the previous, increment the run count
-		for v in copy {
-			if v == last_v {
-				run_count_no_curve += 1;
-			}
-			last_v = v;
+	fn transform(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) {
+		let copy = data.to_vec();
+		reorder(data, &hilbert_order(dimensions));
+
+		// Compare the run counts before and after the hilbert transform - use the pre-transform array if it has a greater run count
+		let use_curve = run_count(&copy, *palette_size) <= run_count(data, *palette_size);
+		if !use_curve {
+			data.copy_from_slice(&copy);
 		}
+		side_info.push(use_curve as u8);
+	}
 
-		for (i, v) in copy.iter().enumerate() {
-			// X and Y swapped for better locality
-			data[[(i >> 8) & 15, i & 15, (i >> 4) & 15].to_hilbert_index(HILBERT_LEVEL)] = *v;
+	fn reverse(data: &mut [u32], dimensions: Dimensions, palette_size: &mut u32, side_info: &mut Vec<u8>) -> anyhow::Result<()> {
+		let use_curve = side_info.pop().context("Missing adaptive Hilbert curve flag")? != 0;
+		if use_curve {
+			HilbertCurve::reverse(data, dimensions, palette_size, side_info)?;
 		}
+		Ok(())
+	}
+}
 
-		last_v = *palette_size + 1;
-		for v in data.iter() {
-			if *v == last_v {
-				run_count += 1;
-			}
-			last_v = *v;
+/// The number of values that are the same as the previous value
+fn run_count(data: &[u32], palette_size: u32) -> usize {
+	let mut run_count = 0;
+	let mut last_v = palette_size + 1;
+	for v in data {
+		if *v == last_v {
+			run_count += 1;
 		}
+		last_v = *v;
+	}
+	run_count
+}
 
-		// Compare the run counts before and after the hilbert transform - use the pre-transform array if it has a greater run count
-		if run_count_no_curve > run_count {
-			for (i, v) in copy.iter().enumerate() {
-				data[i] = *v;
-			}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A section's worth of values with runs and repeats, like block states
+	fn section_values(palette_size: u32) -> Vec<u32> {
+		(0..4096).map(|i| (i as u32 / 7 + i as u32 / 300) % palette_size).collect()
+	}
+
+	/// Transforms and reverses the values, checking the side information is used up, and returns the transformed values
+	fn round_trip<Transformer: IntegerTransformer>(data: &[u32], dimensions: Dimensions, palette_size: u32) -> Vec<u32> {
+		let mut transformed = data.to_vec();
+		let mut palette_size_transformed = palette_size;
+		let mut side_info = vec![];
+		Transformer::transform(&mut transformed, dimensions, &mut palette_size_transformed, &mut side_info);
+		assert!(transformed.iter().all(|v| *v < palette_size_transformed));
+
+		let mut reversed = transformed.clone();
+		Transformer::reverse(&mut reversed, dimensions, &mut palette_size_transformed, &mut side_info).unwrap();
+		assert_eq!(reversed, data);
+		assert!(side_info.is_empty());
+		transformed
+	}
+
+	#[test]
+	fn delta_left_round_trips() {
+		for palette_size in [2, 16, 40, 300] {
+			round_trip::<DeltaLeft>(&section_values(palette_size), Dimensions::SECTION, palette_size);
 		}
-    }
+		// Differences that wrap around below zero
+		let descending: Vec<u32> = (0..4096).map(|i| 39 - i as u32 % 40).collect();
+		round_trip::<DeltaLeft>(&descending, Dimensions::SECTION, 40);
+	}
 
-    fn reverse(data: &mut[u32; 4096], _palette_size: &mut u32) {
-		// TODO: need an extra value to say whether the curve was used!
-		let copy = data.clone();
-		for (i, v) in copy.iter().enumerate() {
-			let [x, y, z] = i.from_hilbert_index(HILBERT_LEVEL);
-			// X and Y swapped for better locality
-			data[get_idx(y as u32, x as u32, z as u32) as usize] = *v;
+	#[test]
+	fn move_to_front_lookbehind_round_trips() {
+		// Repeats 16 and 256 values back, so both lookbehind symbols are used
+		let data: Vec<u32> = (0..4096)
+			.map(|i| {
+				if i / 256 % 2 == 0 {
+					i as u32 % 16
+				} else {
+					i as u32 % 256 / 16 * 3
+				}
+			})
+			.collect();
+		let transformed = round_trip::<MoveToFrontLookbehind>(&data, Dimensions::SECTION, 48);
+		assert!(transformed.contains(&48) && transformed.contains(&49));
+		round_trip::<MoveToFrontLookbehind>(&section_values(40), Dimensions::SECTION, 40);
+	}
+
+	#[test]
+	fn frequency_sorted_palette_round_trips() {
+		// Entry 2 is the most common, then entry 0, and entry 3 never appears
+		let data: Vec<u32> = (0..4096).map(|i| [2, 0, 2, 1, 2, 0][i % 6]).collect();
+		let transformed = round_trip::<FrequencySortedPalette>(&data, Dimensions::SECTION, 4);
+		assert_eq!(&transformed[..6], [0, 1, 0, 2, 0, 1]);
+		round_trip::<FrequencySortedPalette>(&section_values(300), Dimensions::SECTION, 300);
+
+		// The palette order is needed to reverse it
+		let mut side_info = vec![];
+		FrequencySortedPalette::transform(&mut data.clone(), Dimensions::SECTION, &mut 4, &mut side_info);
+		side_info.truncate(2);
+		assert!(FrequencySortedPalette::reverse(&mut data.clone(), Dimensions::SECTION, &mut 4, &mut side_info).is_err());
+	}
+
+	#[test]
+	fn hilbert_curve_adaptive_round_trips() {
+		let flag = |data: &[u32], dimensions: Dimensions| {
+			let mut side_info = vec![];
+			HilbertCurveAdaptive::transform(&mut data.to_vec(), dimensions, &mut 4, &mut side_info);
+			side_info
+		};
+		// Rows that alternate in Z have shorter runs in curve order, and a checkerboard of 2x2x2 cubes has longer runs
+		let stripes: Vec<u32> = (0..4096).map(|i| i as u32 / 16 % 2).collect();
+		let cubes: Vec<u32> = (0..4096)
+			.map(|i| {
+				let (x, y, z) = Dimensions::SECTION.coords(i);
+				((x / 2 + y / 2 + z / 2) % 2) as u32
+			})
+			.collect();
+		assert_eq!(flag(&stripes, Dimensions::SECTION), [0]);
+		assert_eq!(flag(&cubes, Dimensions::SECTION), [1]);
+		for data in [stripes, cubes, section_values(4)] {
+			round_trip::<HilbertCurveAdaptive>(&data, Dimensions::SECTION, 4);
 		}
-    }
-}
\ No newline at end of file
+		round_trip::<HilbertCurveAdaptive>(&section_values(4)[..64], Dimensions::SECTION_BIOMES, 4);
+
+		let mut data = section_values(4);
+		assert!(HilbertCurveAdaptive::reverse(&mut data, Dimensions::SECTION, &mut 4, &mut vec![]).is_err());
+	}
+}
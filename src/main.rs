@@ -1,155 +1,426 @@
-#![feature(drain_filter)]
-
-use arrayvec::ArrayVec;
-use flate2::bufread::ZlibDecoder;
-use byteorder::{BigEndian, ReadBytesExt, ByteOrder};
-use io::{BufWriter, Seek, SeekFrom, Read, Cursor, Write};
-use nbt::{Blob, Value};
+use anyhow::Context;
+use humansize::FileSize;
+use nbt::Value;
 use std::{
+	collections::BTreeMap,
 	fs::File,
-	io::{self, BufReader},
-	path::Path,
-time::Instant, collections::BTreeMap, collections::HashMap};
-use humansize::FileSize;
+	io::{BufReader, BufWriter, Cursor, Write},
+	path::{Path, PathBuf},
+};
 
-mod util;
+mod archive;
+mod blocks;
 mod bytecompressors;
+mod chunk;
+mod context;
+mod dedup;
+mod delta;
+mod derived;
 mod integercoders;
 mod integertransformers;
+mod palette;
+mod priors;
+mod region;
 mod tree;
+mod util;
 
-use bytecompressors::ByteCompressor;
+use bytecompressors::{ArchiveCompressor, ByteCompressor};
 use integercoders::IntegerCoder;
 use integertransformers::IntegerTransformer;
 
-use util::PackedIntegerArrayIter;
-
+use crate::chunk::{unpack_nibbles, BlockStates, ChunkVersion};
+use crate::context::{section_blocks, SectionContext, SectionHistory};
+use crate::dedup::Repeats;
+use crate::delta::Base;
+use crate::priors::Priors;
 use crate::tree::NBTStats;
+use crate::util::Dimensions;
+
+// The pipeline used when writing and reading archives.
+// Blocks are coded against their neighbours, including those in the already coded sections next to them,
+// so they are kept in place; this codes 1-9% smaller than a Hilbert curve with move-to-front on the test regions.
+type ArchiveTransformer = integertransformers::None;
+// Light levels mostly match a neighbouring block, so they are coded the same way
+type ArchiveLightTransformer = integertransformers::None;
+type ArchiveCoder = integercoders::NeighbourContextArithmeticCoding;
+type ArchiveLightCoder = integercoders::NeighbourContextArithmeticCoding;
+
+const USAGE: &str = "Usage:
+	miniworld [bench] [--priors <priors>] [--compressor <compressor>] [--level <level>] [--window <window>]
+	miniworld compress <region.mca> <archive> [--priors <priors>] [--compressor <compressor>] [--level <level>] [--window <window>]
+		[--strip-derived] [--base <archive>]...
+	miniworld decompress <archive> <region.mca> [--base <archive>]...
+	miniworld train-priors <priors> <region.mca>...
+
+--compressor picks none, lzma or zlib (levels 0 to 9), zstd (levels 1 to 22) or brotli (quality 0 to 11 given as the level,
+with a window of 2^10 to 2^24 bytes given as the window). Without --level, zstd uses level 19 and the others their highest.
+Archives use zlib unless another compressor is picked.
+The benchmark compares several compressors unless one is picked.
+
+--priors codes block states starting from how common each block is in the regions train-priors was given.
+The priors are stored in the archive when that makes it smaller, so decompress doesn't need them.
+
+With --base, compress writes a delta archive that only holds the chunks that changed since the given archive,
+and decompress restores a delta archive onto it. A full archive is given first, followed by the deltas made after it in order.";
 
-#[derive(Debug, Copy, Clone)]
-struct ChunkPosition {
-	offset: u32,
-	sector_count: u8,
+fn main() -> anyhow::Result<()> {
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
+	let (args, options) = load_options(&args)?;
+	match args.as_slice() {
+		[] | ["bench"] => benchmark(options),
+		["compress", region_path, archive_path] => compress(Path::new(region_path), Path::new(archive_path), &options),
+		["decompress", archive_path, region_path] => decompress(Path::new(archive_path), Path::new(region_path), &options),
+		["train-priors", priors_path, region_paths @ ..] if !region_paths.is_empty() => {
+			train_priors(Path::new(priors_path), region_paths)
+		}
+		_ => anyhow::bail!(USAGE),
+	}
 }
 
-#[derive(Debug, Clone)]
-struct PaletteValue {
-	color: [u8; 3],
-	nbt: Value,
+#[derive(Default)]
+struct Options {
+	/// The compressor picked with --compressor, --level and --window
+	compressor: Option<ArchiveCompressor>,
+	/// The block state priors given with --priors, which archives are coded with
+	priors: Option<Priors>,
+	/// Leave heightmaps and light out of archives, to be recomputed when decompressing
+	strip_derived: bool,
+	/// A full archive followed by the delta archives made after it,
+	/// restored as the snapshot that archives are written or read as deltas against
+	base: Vec<PathBuf>,
 }
 
-fn main() -> anyhow::Result<()> {
+/// Loads the priors given with --priors and reads the other options, returning the remaining arguments
+fn load_options<'a>(args: &[&'a str]) -> anyhow::Result<(Vec<&'a str>, Options)> {
+	let mut remaining = vec![];
+	let mut options = Options::default();
+	let (mut compressor, mut level, mut window) = (None, None, None);
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match *arg {
+			"--compressor" => compressor = Some(*args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?),
+			"--level" => {
+				level = Some(
+					args.next()
+						.ok_or_else(|| anyhow::anyhow!(USAGE))?
+						.parse()
+						.context("Invalid level")?,
+				)
+			}
+			"--window" => {
+				window = Some(
+					args.next()
+						.ok_or_else(|| anyhow::anyhow!(USAGE))?
+						.parse()
+						.context("Invalid window")?,
+				)
+			}
+			"--priors" => {
+				let priors_path = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+				options.priors = Some(Priors::read(&mut BufReader::new(File::open(priors_path)?))?);
+			}
+			"--strip-derived" => options.strip_derived = true,
+			"--base" => options
+				.base
+				.push(PathBuf::from(args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?)),
+			arg => remaining.push(arg),
+		}
+	}
+	options.compressor = match (compressor, level, window) {
+		(Some(compressor), level, window) => Some(ArchiveCompressor::new(compressor, level, window)?),
+		(None, None, None) => None,
+		_ => anyhow::bail!("--level and --window need a --compressor"),
+	};
+	Ok((remaining, options))
+}
+
+impl Options {
+	/// The compressor archives are written with: the picked one, or zlib as archives always used to be
+	fn archive_compressor(&self) -> anyhow::Result<ArchiveCompressor> {
+		match &self.compressor {
+			Some(compressor) => Ok(compressor.clone()),
+			None => ArchiveCompressor::new("zlib", None, None),
+		}
+	}
+}
+
+fn train_priors(priors_path: &Path, region_paths: &[&str]) -> anyhow::Result<()> {
+	let mut priors = Priors::new();
+	for region_path in region_paths {
+		for chunk in region::read_region(Path::new(region_path))? {
+			let version = ChunkVersion::of(&chunk.data);
+			for section in version.sections(&chunk.data).into_iter().flatten() {
+				if let Some((_, palette, arr)) = section_blocks(version, section) {
+					priors.accumulate(&palette, &arr);
+				}
+			}
+		}
+	}
+
+	priors.normalise();
+	let mut writer = BufWriter::new(File::create(priors_path)?);
+	priors.write(&mut writer)?;
+	writer.flush()?;
+	println!("Trained priors for {} block states", priors.len());
+	Ok(())
+}
+
+fn compress(region_path: &Path, archive_path: &Path, options: &Options) -> anyhow::Result<()> {
+	let chunks = region::read_region(region_path)?;
+	let compressor = options.archive_compressor()?;
+	let base = load_base(&options.base)?;
+	let mut writer = BufWriter::new(File::create(archive_path)?);
+	archive::write_archive::<ArchiveTransformer, ArchiveLightTransformer, ArchiveCoder, ArchiveLightCoder>(
+		&chunks,
+		base.as_ref(),
+		&mut writer,
+		&compressor,
+		options.priors.as_ref(),
+		options.strip_derived,
+	)?;
+	writer.flush()?;
+
+	let orig_size = std::fs::metadata(region_path)?.len();
+	let archive_size = std::fs::metadata(archive_path)?.len();
+	println!(
+		"Compressed {} chunks: {} -> {}",
+		chunks.len(),
+		orig_size.file_size(humansize::file_size_opts::DECIMAL).unwrap(),
+		archive_size.file_size(humansize::file_size_opts::DECIMAL).unwrap()
+	);
+	Ok(())
+}
+
+fn decompress(archive_path: &Path, region_path: &Path, options: &Options) -> anyhow::Result<()> {
+	let base = load_base(&options.base)?;
+	let mut reader = BufReader::new(File::open(archive_path)?);
+	let chunks = archive::read_archive::<ArchiveTransformer, ArchiveLightTransformer, ArchiveCoder, ArchiveLightCoder>(
+		&mut reader,
+		base.as_ref(),
+	)?;
+	region::write_region(region_path, &chunks)?;
+	println!("Decompressed {} chunks", chunks.len());
+	Ok(())
+}
+
+/// Restores a full archive and each delta archive after it in turn, returning the last snapshot as the base for another delta
+fn load_base(archive_paths: &[PathBuf]) -> anyhow::Result<Option<Base>> {
+	let mut base = None;
+	for archive_path in archive_paths {
+		let archive = std::fs::read(archive_path)?;
+		let chunks = archive::read_archive::<ArchiveTransformer, ArchiveLightTransformer, ArchiveCoder, ArchiveLightCoder>(
+			&mut Cursor::new(&archive),
+			base.as_ref(),
+		)
+		.with_context(|| format!("Failed to restore base archive {:?}", archive_path))?;
+		base = Some(Base::new(chunks, &archive));
+	}
+	Ok(base)
+}
+
+fn benchmark(options: Options) -> anyhow::Result<()> {
+	let compressors = match options.compressor {
+		Some(_) => vec![options.archive_compressor()?],
+		None => vec![
+			ArchiveCompressor::new("none", None, None)?,
+			// ArchiveCompressor::new("lzma", None, None)?,
+			ArchiveCompressor::new("zlib", None, None)?,
+			// ArchiveCompressor::new("zlib", Some(6), None)?,
+			ArchiveCompressor::new("zstd", Some(3), None)?,
+			ArchiveCompressor::new("zstd", Some(19), None)?,
+			// ArchiveCompressor::new("zstd", Some(22), None)?,
+			ArchiveCompressor::new("brotli", Some(5), None)?,
+			ArchiveCompressor::new("brotli", Some(11), None)?,
+			// ArchiveCompressor::new("brotli", Some(11), Some(24))?,
+		],
+	};
 	for file in std::fs::read_dir(Path::new("bench"))? {
 		let file = file?;
 		println!("Reading file {:?}", &file.path());
-		fn bench_3<Transformer: IntegerTransformer, Coder: IntegerCoder>(orig_path: &Path) -> anyhow::Result<()> {
-			println!("\t\tCompressor: None");
-			benchmark_file::<Transformer, Coder, bytecompressors::None>(orig_path)?;
-			// println!("\t\tCompressor: LZMA");
-			// benchmark_file::<Transformer, Coder, bytecompressors::LZMA>(orig_path)?;
-			println!("\t\tCompressor: Zlib");
-			benchmark_file::<Transformer, Coder, bytecompressors::Zlib>(orig_path)?;
+		fn bench_3<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+			orig_path: &Path,
+			compressors: &[ArchiveCompressor],
+			priors: Option<&Priors>,
+		) -> anyhow::Result<()> {
+			for compressor in compressors {
+				println!("\t\tCompressor: {}", compressor);
+				benchmark_file::<Transformer, Coder>(orig_path, compressor, priors)?;
+			}
 			Ok(())
 		}
-		fn bench_2<Transformer: IntegerTransformer>(orig_path: &Path) -> anyhow::Result<()> {
+		fn bench_2<Transformer: IntegerTransformer>(
+			orig_path: &Path,
+			compressors: &[ArchiveCompressor],
+			priors: Option<&Priors>,
+		) -> anyhow::Result<()> {
 			println!("\tCoder: Arithmetic");
-			bench_3::<Transformer, integercoders::ArithmeticCoding>(orig_path)?;
+			bench_3::<Transformer, integercoders::ArithmeticCoding>(orig_path, compressors, None)?;
+			if priors.is_some() {
+				println!("\tCoder: Arithmetic with block state priors");
+				bench_3::<Transformer, integercoders::PriorArithmeticCoding>(orig_path, compressors, priors)?;
+			}
+			println!("\tCoder: Arithmetic with neighbour contexts");
+			bench_3::<Transformer, integercoders::NeighbourContextArithmeticCoding>(orig_path, compressors, None)?;
+			println!("\tCoder: Arithmetic with neighbour contexts, without neighbouring sections");
+			bench_3::<Transformer, integercoders::NoContext<integercoders::NeighbourContextArithmeticCoding>>(
+				orig_path,
+				compressors,
+				None,
+			)?;
+			println!("\tCoder: Run lengths with arithmetic coding");
+			bench_3::<Transformer, integercoders::RunLengthArithmeticCoding>(orig_path, compressors, None)?;
+			println!("\tCoder: rANS");
+			bench_3::<Transformer, integercoders::Rans>(orig_path, compressors, None)?;
+			println!("\tCoder: Huffman");
+			bench_3::<Transformer, integercoders::Huffman>(orig_path, compressors, None)?;
 			println!("\tCoder: Bytewise");
-			bench_3::<Transformer, integercoders::Bytewise>(orig_path)?;
+			bench_3::<Transformer, integercoders::Bytewise>(orig_path, compressors, None)?;
+			println!("\tCoder: Packed integers");
+			bench_3::<Transformer, integercoders::PackedIntegers>(orig_path, compressors, None)?;
+			println!("\tCoder: Simple16");
+			bench_3::<Transformer, integercoders::Simple16>(orig_path, compressors, None)?;
 			Ok(())
 		}
 		println!("Transformer: None");
-		bench_2::<integertransformers::None>(&file.path())?;
+		bench_2::<integertransformers::None>(&file.path(), &compressors, options.priors.as_ref())?;
 		// println!("Transformer: Delta of prev value");
-		// bench_2::<integertransformers::DeltaLeft>(&file.path())?;
+		// bench_2::<integertransformers::DeltaLeft>(&file.path(), &compressors, options.priors.as_ref())?;
+		println!("Transformer: Delta of the value above");
+		bench_2::<integertransformers::DeltaAbove>(&file.path(), &compressors, options.priors.as_ref())?;
+		println!("Transformer: Frequency sorted palette");
+		bench_2::<integertransformers::FrequencySortedPalette>(&file.path(), &compressors, options.priors.as_ref())?;
+		println!("Transformer: Hilbert curve with frequency sorted palette");
+		bench_2::<(integertransformers::HilbertCurve, integertransformers::FrequencySortedPalette)>(
+			&file.path(),
+			&compressors,
+			options.priors.as_ref(),
+		)?;
 		println!("Transformer: Move-to-front");
-		bench_2::<integertransformers::MoveToFront>(&file.path())?;
+		bench_2::<integertransformers::MoveToFront>(&file.path(), &compressors, options.priors.as_ref())?;
 		// println!("Transformer: Move-to-front with 16/256 lookbehind");
-		// bench_2::<integertransformers::MoveToFrontLookbehind>(&file.path())?;
+		// bench_2::<integertransformers::MoveToFrontLookbehind>(&file.path(), &compressors, options.priors.as_ref())?;
 		// println!("Transformer: Z-order curve");
-		// bench_2::<integertransformers::ZOrderCurve>(&file.path())?;
+		// bench_2::<integertransformers::ZOrderCurve>(&file.path(), &compressors, options.priors.as_ref())?;
 		// println!("Transformer: Z-order curve with Move-to-front");
-		// bench_2::<(integertransformers::ZOrderCurve, integertransformers::MoveToFront)>(&file.path())?;
-		// println!("Transformer: Hilbert curve");
-		// bench_2::<integertransformers::HilbertCurve>(&file.path())?;
+		// bench_2::<(integertransformers::ZOrderCurve, integertransformers::MoveToFront)>(
+		// 	&file.path(),
+		// 	&compressors,
+		// 	options.priors.as_ref(),
+		// )?;
+		println!("Transformer: Hilbert curve");
+		bench_2::<integertransformers::HilbertCurve>(&file.path(), &compressors, options.priors.as_ref())?;
 		println!("Transformer: Hilbert curve with Move-to-front");
-		bench_2::<(integertransformers::HilbertCurve, integertransformers::MoveToFront)>(&file.path())?;
+		bench_2::<(integertransformers::HilbertCurve, integertransformers::MoveToFront)>(
+			&file.path(),
+			&compressors,
+			options.priors.as_ref(),
+		)?;
 		println!("Transformer: Adaptive Hilbert curve with Move-to-front");
-		bench_2::<(integertransformers::HilbertCurveAdaptive, integertransformers::MoveToFront)>(&file.path())?;
+		bench_2::<(integertransformers::HilbertCurveAdaptive, integertransformers::MoveToFront)>(
+			&file.path(),
+			&compressors,
+			options.priors.as_ref(),
+		)?;
 	}
 
 	Ok(())
 }
 
-fn benchmark_file<Transformer: IntegerTransformer, Coder: IntegerCoder, Compressor: ByteCompressor>(orig_path: &Path) -> anyhow::Result<()> {
-	let file = File::open(orig_path)?;
-	let mut buf_reader = BufReader::new(file);
-
-	let mut chunk_positions = vec![];
-
-	for _ in 0..1024 {
-		let value = buf_reader.read_u32::<BigEndian>()?;
-		let offset = value >> 8;
-		let sector_count = (value & 0b1111_1111) as u8;
-
-		if sector_count == 0 || offset == 0 {
-			continue;
-		}
-
-		chunk_positions.push(ChunkPosition { offset, sector_count });
-	}
-
-	//println!("Found {} chunks", chunk_positions.len());
-
-	let orig_size = buf_reader.seek(SeekFrom::End(0))?;
-	//println!("Original size: {}", orig_size.file_size(humansize::file_size_opts::DECIMAL).unwrap());
-	let mut unpadded_size = 0;
-	let mut decompressed_size = 0;
+fn benchmark_file<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	orig_path: &Path,
+	compressor: &impl ByteCompressor,
+	priors: Option<&Priors>,
+) -> anyhow::Result<()> {
+	let chunks = region::read_region(orig_path)?;
 
 	let mut final_size = 0;
+	let mut biomes_size = 0;
+	let mut block_light_size = 0;
+	let mut sky_light_size = 0;
 	let mut palette_sizes_map: BTreeMap<u32, u64> = BTreeMap::new();
 
 	let mut nbt_stats = NBTStats::new();
+	let mut history = SectionHistory::new();
+	let mut repeats = Repeats::new();
+
+	for chunk in &chunks {
+		let version = ChunkVersion::of(&chunk.data);
+		if let Some(level) = version.level(&chunk.data) {
+			nbt_stats.accumulate(level);
+		}
 
-	for pos in &chunk_positions {
-		buf_reader.seek(SeekFrom::Start((pos.offset * 4096) as u64))?;
-		let _length = buf_reader.read_u32::<BigEndian>()?;
-		let _compression_type = buf_reader.read_u8()?;
-		// TODO: handle non-zlib
-
-		let mut decoder = ZlibDecoder::new(&mut buf_reader);
-		let chunk_data = Blob::from_reader(&mut decoder)?;
-
-		unpadded_size += decoder.total_in();
-		decompressed_size += decoder.total_out();
-
-		if let Some(value) = chunk_data.get("Level") {
-			let mut value = value.clone();
-			nbt_stats.accumulate(&value);
-
-			if let Value::Compound(ref mut level_map) = value {
-				if let Some(Value::List(sections)) = level_map.get_mut("Sections") {
-					for section in sections {
-						if let Value::Compound(section) = section {
-							if let Some(Value::List(palette)) = section.get("Palette") {
-								let palette_length = palette.len();
-								if let Some(Value::LongArray(data)) = section.get("BlockStates") {
-									run_tests::<Transformer, Coder, Compressor>(data, palette_length as u32, &mut final_size, &mut palette_sizes_map)?;
-								}
-							}
+		for section in version.sections(&chunk.data).into_iter().flatten() {
+			if let Some((y, palette, arr)) = section_blocks(version, section) {
+				// Archives store repeated sections as references, so only the first copy is coded
+				if palette.len() <= 1 || !repeats.count(archive::section_key(&palette, &arr)?) {
+					let mut context = history.context(chunk.index, Some(y), &palette);
+					context.prior_counts = priors.map(|priors| priors.initial_counts(&palette));
+					let size =
+						run_tests::<Transformer, Coder>(&arr, Dimensions::SECTION, palette.len() as u32, &context, compressor)?;
+					final_size += size as i64;
+					*palette_sizes_map.entry(palette.len() as u32).or_insert(0) += size as u64;
+				}
+				history.insert(chunk.index, y, palette, arr);
+			}
+			if let Some(BlockStates {
+				palette,
+				data: Some(data),
+			}) = version.biomes(section)
+			{
+				if let Some(arr) = archive::unpack_biomes(data, palette.len() as u32, version.packing()) {
+					biomes_size += run_tests::<Transformer, Coder>(
+						&arr,
+						Dimensions::SECTION_BIOMES,
+						palette.len() as u32,
+						&SectionContext::default(),
+						compressor,
+					)? as i64;
+				}
+			}
+			if let Value::Compound(section) = section {
+				for (key, size) in [("BlockLight", &mut block_light_size), ("SkyLight", &mut sky_light_size)] {
+					if let Some(Value::ByteArray(light)) = section.get(key) {
+						if light.len() == 2048 {
+							*size += run_tests::<Transformer, Coder>(
+								&unpack_nibbles(light),
+								Dimensions::SECTION,
+								16,
+								&SectionContext::default(),
+								compressor,
+							)? as i64;
 						}
 					}
 				}
 			}
 		}
-
 	}
 
 	nbt_stats.print();
-	
-	//println!("Unpadded size: {}", unpadded_size.file_size(humansize::file_size_opts::DECIMAL).unwrap());
-	//println!("Decompressed size: {}", decompressed_size.file_size(humansize::file_size_opts::DECIMAL).unwrap());
-	println!("\t\tBlockstates final size: {}", final_size.file_size(humansize::file_size_opts::DECIMAL).unwrap());
+
+	println!(
+		"\t\tBlockstates final size: {}",
+		final_size.file_size(humansize::file_size_opts::DECIMAL).unwrap()
+	);
+	let (sections, duplicates) = repeats.duplicates();
+	println!(
+		"\t\tDuplicate sections: {} of {} ({:.1}%)",
+		duplicates,
+		sections,
+		duplicates as f64 * 100.0 / sections.max(1) as f64
+	);
+	println!(
+		"\t\tBiomes final size: {}",
+		biomes_size.file_size(humansize::file_size_opts::DECIMAL).unwrap()
+	);
+	println!(
+		"\t\tBlock light final size: {}",
+		block_light_size.file_size(humansize::file_size_opts::DECIMAL).unwrap()
+	);
+	println!(
+		"\t\tSky light final size: {}",
+		sky_light_size.file_size(humansize::file_size_opts::DECIMAL).unwrap()
+	);
 	// println!("\t\tPalette length / size distribution: ");
 	// for v in palette_sizes_map {
 	// 	println!("\t\t\t{}, {}" , v.0, v.1);
@@ -158,45 +429,73 @@ fn benchmark_file<Transformer: IntegerTransformer, Coder: IntegerCoder, Compress
 	Ok(())
 }
 
-fn run_tests<Transformer: IntegerTransformer, Coder: IntegerCoder, Compressor: ByteCompressor>(data: &Vec<i64>, palette_length: u32, final_size: &mut i64, palette_sizes_map: &mut BTreeMap<u32, u64>) -> anyhow::Result<()> {
+/// Encodes and compresses an array, verifying it round trips, and returns its compressed size
+fn run_tests<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	arr_orig: &[u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	context: &SectionContext,
+	compressor: &impl ByteCompressor,
+) -> anyhow::Result<usize> {
 	if palette_length <= 1 {
-		return Ok(());
+		return Ok(0);
 	}
-	
-	let num_bits = match (palette_length as f64).log2().ceil() as usize {
-		0..=4 => 4,
-		x => x,
-	};
-
-	let decoded_data: ArrayVec<u32, 4096> = PackedIntegerArrayIter::new(data.iter(), num_bits as u8)
-		//.map(|value| value as usize)
-		.inspect(|value| assert!(*value < palette_length, "Invalid palette value"))
-		.take(4096)
-		.collect();
-	let mut arr = decoded_data.into_inner().unwrap();
-	let mut palette_size_transformed = palette_length;
-	// let arr_orig = arr.clone();
-	
-	Transformer::transform(&mut arr, &mut palette_size_transformed);
-
-	// let arr_transformed = arr.clone();
-
-	// Transformer::reverse(&mut arr, &mut palette_size_transformed);
-	// if !arr.eq(&arr_orig) {
-	// 	println!("{:?}", arr_orig);
-	// 	println!("{:?}", arr_transformed);
-	// 	println!("{:?}", arr);
-	// 	panic!("Oh no!");
-	// }
 
+	let mut arr = arr_orig.to_vec();
 	let mut encoded = vec![];
-	Coder::encode(&arr, &mut encoded, palette_size_transformed);
+	let palette_size_transformed =
+		archive::encode_values::<Transformer, Coder>(&mut arr, dimensions, palette_length, context, &mut encoded)?;
 
 	let mut compressed = vec![];
-	Compressor::compress(&encoded, &mut compressed);
+	compressor.compress(&encoded, &mut compressed);
+
+	let mut decompressed = vec![];
+	compressor.decompress(&compressed, &mut decompressed)?;
+	if decompressed != encoded {
+		anyhow::bail!(
+			"Compressor round trip failed: {} bytes in, {} bytes out",
+			encoded.len(),
+			decompressed.len()
+		);
+	}
+	verify_round_trip::<Transformer, Coder>(
+		arr_orig,
+		dimensions,
+		palette_length,
+		palette_size_transformed,
+		context,
+		&decompressed,
+	)?;
+
+	Ok(compressed.len())
+}
 
-	*final_size += compressed.len() as i64;
-	*palette_sizes_map.entry(palette_length).or_insert(0) += compressed.len() as u64;
+/// Decodes and reverses the transform, checking it reproduces the input, so broken reverse implementations can't go unnoticed
+fn verify_round_trip<Transformer: IntegerTransformer, Coder: IntegerCoder>(
+	arr_orig: &[u32],
+	dimensions: Dimensions,
+	palette_length: u32,
+	palette_size_transformed: u32,
+	context: &SectionContext,
+	encoded: &[u8],
+) -> anyhow::Result<()> {
+	let mut arr = vec![0u32; arr_orig.len()];
+	archive::decode_values::<Transformer, Coder>(
+		encoded,
+		&mut arr,
+		dimensions,
+		palette_length,
+		palette_size_transformed,
+		context,
+	)?;
+	if arr != arr_orig {
+		anyhow::bail!(
+			"Decoding round trip failed with transformed palette size {}:\n{:?}\n{:?}",
+			palette_size_transformed,
+			arr_orig,
+			arr
+		);
+	}
 
 	Ok(())
-}
\ No newline at end of file
+}
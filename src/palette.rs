@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use nbt::Value;
+
+/// Every distinct block state palette entry in a region, so that section palettes can be stored as indices into it
+#[derive(Default)]
+pub struct BlockStateDictionary {
+	entries: Vec<Value>,
+	/// Entry indices by their NBT encoding, as NBT values can't be hashed
+	indices: HashMap<Vec<u8>, u32>,
+}
+
+impl BlockStateDictionary {
+	pub fn new() -> BlockStateDictionary {
+		BlockStateDictionary::default()
+	}
+
+	/// A dictionary read back from its entries, which can only be looked up
+	pub fn from_entries(entries: Vec<Value>) -> BlockStateDictionary {
+		BlockStateDictionary {
+			entries,
+			indices: HashMap::new(),
+		}
+	}
+
+	pub fn entries(&self) -> &[Value] {
+		&self.entries
+	}
+
+	/// The index of a palette entry, adding it if it hasn't been seen yet
+	pub fn intern(&mut self, entry: &Value) -> anyhow::Result<u32> {
+		let mut key = vec![entry.id()];
+		entry.to_writer(&mut key)?;
+		let entries = &mut self.entries;
+		Ok(*self.indices.entry(key).or_insert_with(|| {
+			entries.push(entry.clone());
+			entries.len() as u32 - 1
+		}))
+	}
+
+	pub fn get(&self, index: u32) -> Option<&Value> {
+		self.entries.get(index as usize)
+	}
+}
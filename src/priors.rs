@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use anyhow::bail;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use nbt::Value;
+
+use crate::util::{read_varint, write_varint};
+
+const MAGIC: &[u8; 4] = b"MWPR";
+const VERSION: u8 = 1;
+/// Weights are scaled to sum to this before they are written
+const TOTAL_WEIGHT: u64 = 1 << 24;
+/// How many already seen values the priors count as when initialising a model
+const PRIOR_STRENGTH: f64 = 16.0;
+
+// Priors file layout:
+// - magic, version, entry count
+// - for each entry: name length, name (UTF-8), weight, all varints
+
+/// How common each block state is, by palette entry name, learned from a corpus of regions
+#[derive(Debug, Default)]
+pub struct Priors {
+	weights: HashMap<String, u64>,
+}
+
+impl Priors {
+	pub fn new() -> Priors {
+		Priors::default()
+	}
+
+	/// The number of block states with a probability
+	pub fn len(&self) -> usize {
+		self.weights.len()
+	}
+
+	/// Counts the values of a section
+	pub fn accumulate(&mut self, palette: &[Value], arr: &[u32]) {
+		let mut counts = vec![0u64; palette.len()];
+		for v in arr {
+			counts[*v as usize] += 1;
+		}
+		for (entry, count) in palette.iter().zip(counts) {
+			if let Some(name) = palette_entry_name(entry) {
+				*self.weights.entry(name).or_insert(0) += count;
+			}
+		}
+	}
+
+	/// Initial model counts for a section's palette, with the priors' probabilities renormalised over the palette
+	/// and unknown entries given the minimum count
+	pub fn initial_counts(&self, palette: &[Value]) -> Vec<u32> {
+		let weights: Vec<u64> = palette
+			.iter()
+			.map(|entry| {
+				palette_entry_name(entry)
+					.and_then(|name| self.weights.get(&name))
+					.copied()
+					.unwrap_or(0)
+			})
+			.collect();
+		let total: u64 = weights.iter().sum();
+		weights
+			.iter()
+			.map(|weight| {
+				if total == 0 {
+					1
+				} else {
+					((*weight as f64 / total as f64 * PRIOR_STRENGTH).round() as u32).max(1)
+				}
+			})
+			.collect()
+	}
+
+	/// The priors of only the entries in the given palettes, which is all that coding them needs,
+	/// as initial_counts renormalises over each palette
+	pub fn for_palettes<'a>(&self, palettes: impl IntoIterator<Item = &'a [Value]>) -> Priors {
+		let mut weights = HashMap::new();
+		for entry in palettes.into_iter().flatten() {
+			if let Some((name, weight)) =
+				palette_entry_name(entry).and_then(|name| self.weights.get(&name).map(|weight| (name, *weight)))
+			{
+				weights.insert(name, weight);
+			}
+		}
+		Priors { weights }
+	}
+
+	/// Scales the weights to sum to TOTAL_WEIGHT, dropping the ones that round to nothing, so they fit in fewer bytes.
+	/// Written priors read back the same, so an archive is decoded with exactly the priors it was encoded with.
+	pub fn normalise(&mut self) {
+		let total: u64 = self.weights.values().sum();
+		for weight in self.weights.values_mut() {
+			*weight = (*weight as u128 * TOTAL_WEIGHT as u128 / total.max(1) as u128) as u64;
+		}
+		self.weights.retain(|_, weight| *weight > 0);
+	}
+
+	pub fn write(&self, dest: &mut impl Write) -> anyhow::Result<()> {
+		let mut entries: Vec<(&String, &u64)> = self.weights.iter().collect();
+		// Sorted so the same corpus always gives the same file
+		entries.sort();
+
+		dest.write_all(MAGIC)?;
+		dest.write_u8(VERSION)?;
+		write_varint(dest, entries.len() as u64)?;
+		for (name, weight) in entries {
+			write_varint(dest, name.len() as u64)?;
+			dest.write_all(name.as_bytes())?;
+			write_varint(dest, *weight)?;
+		}
+		Ok(())
+	}
+
+	pub fn read(src: &mut impl Read) -> anyhow::Result<Priors> {
+		let mut magic = [0u8; 4];
+		src.read_exact(&mut magic)?;
+		if &magic != MAGIC {
+			bail!("Not a miniworld priors file");
+		}
+		let version = src.read_u8()?;
+		if version != VERSION {
+			bail!("Unsupported priors version {}", version);
+		}
+
+		let mut weights = HashMap::new();
+		for _ in 0..read_varint(src)? {
+			// Bounded by the data that's there rather than the length it claims, as priors are read from archives
+			let length = read_varint(src)?;
+			let mut name = vec![];
+			src.by_ref().take(length).read_to_end(&mut name)?;
+			if name.len() as u64 != length {
+				bail!("Unexpected end of priors");
+			}
+			weights.insert(String::from_utf8(name)?, read_varint(src)?);
+		}
+		Ok(Priors { weights })
+	}
+}
+
+/// The block name of a palette entry, or the numeric state of a pre-flattening palette entry
+fn palette_entry_name(entry: &Value) -> Option<String> {
+	match entry {
+		Value::Compound(entry) => match entry.get("Name") {
+			Some(Value::String(name)) => Some(name.clone()),
+			_ => None,
+		},
+		Value::Short(state) => Some(format!("legacy:{}", *state as u16)),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn compound_entry(name: &str) -> Value {
+		Value::Compound(
+			vec![("Name".to_string(), Value::String(name.to_string()))]
+				.into_iter()
+				.collect(),
+		)
+	}
+
+	#[test]
+	fn priors_round_trip() {
+		let mut priors = Priors::new();
+		let palette = [
+			compound_entry("minecraft:stone"),
+			compound_entry("minecraft:dirt"),
+			Value::Short(17),
+		];
+		priors.accumulate(&palette, &[0, 0, 0, 1, 2, 2]);
+		priors.normalise();
+		let mut bytes = vec![];
+		priors.write(&mut bytes).unwrap();
+		let read = Priors::read(&mut Cursor::new(bytes)).unwrap();
+		assert_eq!(read.weights, priors.weights);
+		assert_eq!(read.initial_counts(&palette), [8, 3, 5]);
+	}
+
+	#[test]
+	fn names_longer_than_the_data_are_an_error() {
+		let mut bytes = MAGIC.to_vec();
+		bytes.push(VERSION);
+		write_varint(&mut bytes, 1).unwrap();
+		write_varint(&mut bytes, u64::MAX >> 1).unwrap();
+		bytes.extend_from_slice(b"stone");
+		assert!(Priors::read(&mut Cursor::new(bytes)).is_err());
+	}
+}
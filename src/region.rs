@@ -0,0 +1,391 @@
+use std::{
+	convert::TryFrom,
+	fs::File,
+	io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{
+	bufread::{GzDecoder, ZlibDecoder},
+	write::ZlibEncoder,
+	Compression,
+};
+use nbt::Value;
+use num_enum::TryFromPrimitive;
+
+const SECTOR_SIZE: u64 = 4096;
+/// Set in the compression type when the chunk is stored in a separate c.x.z.mcc file
+const EXTERNAL_FLAG: u8 = 0x80;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+enum CompressionType {
+	Gzip = 1,
+	Zlib = 2,
+	Uncompressed = 3,
+	/// LZ4 in the block stream format written by lz4-java's LZ4BlockOutputStream
+	Lz4 = 4,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct ChunkPosition {
+	offset: u32,
+	sector_count: u8,
+}
+
+/// A single chunk of an Anvil region file
+#[derive(Debug, Clone)]
+pub struct Chunk {
+	/// Index into the region header, x + z * 32
+	pub index: u16,
+	pub timestamp: u32,
+	/// The root compound of the chunk NBT
+	pub data: Value,
+}
+
+pub fn read_region(path: &Path) -> anyhow::Result<Vec<Chunk>> {
+	let file = File::open(path)?;
+	let mut buf_reader = BufReader::new(file);
+
+	let mut chunk_positions = vec![];
+	for _ in 0..1024 {
+		let value = buf_reader.read_u32::<BigEndian>()?;
+		let offset = value >> 8;
+		let sector_count = (value & 0b1111_1111) as u8;
+		chunk_positions.push(ChunkPosition { offset, sector_count });
+	}
+
+	let mut timestamps = vec![];
+	for _ in 0..1024 {
+		timestamps.push(buf_reader.read_u32::<BigEndian>()?);
+	}
+
+	let mut chunks = vec![];
+	for (index, pos) in chunk_positions.iter().enumerate() {
+		if pos.sector_count == 0 || pos.offset == 0 {
+			continue;
+		}
+
+		buf_reader.seek(SeekFrom::Start(pos.offset as u64 * SECTOR_SIZE))?;
+		let length = buf_reader.read_u32::<BigEndian>()?;
+		let compression_type = buf_reader.read_u8()?;
+
+		let compressed = if compression_type & EXTERNAL_FLAG != 0 {
+			let external_path = external_chunk_path(path, index as u16)?;
+			std::fs::read(&external_path).with_context(|| format!("Failed to read external chunk {:?}", external_path))?
+		} else {
+			let mut compressed = vec![0u8; (length as usize).saturating_sub(1)];
+			buf_reader.read_exact(&mut compressed)?;
+			compressed
+		};
+
+		let compression_type = CompressionType::try_from(compression_type & !EXTERNAL_FLAG)
+			.map_err(|_| anyhow!("Unknown compression type {} for chunk {}", compression_type, index))?;
+		let data = decompress_chunk(compression_type, &compressed).with_context(|| format!("Failed to read chunk {}", index))?;
+
+		chunks.push(Chunk {
+			index: index as u16,
+			timestamp: timestamps[index],
+			data,
+		});
+	}
+
+	Ok(chunks)
+}
+
+pub fn write_region(path: &Path, chunks: &[Chunk]) -> anyhow::Result<()> {
+	let mut locations = [0u32; 1024];
+	let mut timestamps = [0u32; 1024];
+	let mut sectors = vec![];
+
+	for chunk in chunks {
+		let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+		write_nbt(&mut encoder, &chunk.data)?;
+		let compressed = encoder.finish()?;
+
+		let mut payload = vec![];
+		payload.write_u32::<BigEndian>(compressed.len() as u32 + 1)?;
+		payload.write_u8(CompressionType::Zlib as u8)?;
+		payload.extend_from_slice(&compressed);
+
+		let mut sector_count = (payload.len() as u64).div_ceil(SECTOR_SIZE);
+		if sector_count > 255 {
+			// Too large for the region file, so store it externally like the game does
+			std::fs::write(external_chunk_path(path, chunk.index)?, &compressed)?;
+			payload.clear();
+			payload.write_u32::<BigEndian>(1)?;
+			payload.write_u8(CompressionType::Zlib as u8 | EXTERNAL_FLAG)?;
+			sector_count = 1;
+		}
+		payload.resize((sector_count * SECTOR_SIZE) as usize, 0);
+
+		// The header takes up the first two sectors
+		let offset = 2 + sectors.len() as u64 / SECTOR_SIZE;
+		locations[chunk.index as usize] = (offset as u32) << 8 | sector_count as u32;
+		timestamps[chunk.index as usize] = chunk.timestamp;
+		sectors.extend_from_slice(&payload);
+	}
+
+	let mut writer = BufWriter::new(File::create(path)?);
+	for location in &locations {
+		writer.write_u32::<BigEndian>(*location)?;
+	}
+	for timestamp in &timestamps {
+		writer.write_u32::<BigEndian>(*timestamp)?;
+	}
+	writer.write_all(&sectors)?;
+	writer.flush()?;
+
+	Ok(())
+}
+
+fn decompress_chunk(compression_type: CompressionType, compressed: &[u8]) -> anyhow::Result<Value> {
+	match compression_type {
+		CompressionType::Gzip => read_nbt(&mut GzDecoder::new(compressed)),
+		CompressionType::Zlib => read_nbt(&mut ZlibDecoder::new(compressed)),
+		CompressionType::Uncompressed => read_nbt(&mut Cursor::new(compressed)),
+		CompressionType::Lz4 => read_nbt(&mut Cursor::new(decompress_lz4_blocks(compressed)?)),
+	}
+}
+
+/// Decompresses the LZ4BlockOutputStream format: a sequence of blocks, each with a 21 byte header, ending with an empty block
+fn decompress_lz4_blocks(compressed: &[u8]) -> anyhow::Result<Vec<u8>> {
+	const COMPRESSION_METHOD_RAW: u8 = 0x10;
+	const COMPRESSION_METHOD_LZ4: u8 = 0x20;
+
+	let mut reader = Cursor::new(compressed);
+	let mut decompressed = vec![];
+	loop {
+		let mut magic = [0u8; 8];
+		reader.read_exact(&mut magic)?;
+		if &magic != b"LZ4Block" {
+			bail!("Invalid LZ4 block magic");
+		}
+		let token = reader.read_u8()?;
+		let compressed_length = reader.read_u32::<LittleEndian>()? as usize;
+		let decompressed_length = reader.read_u32::<LittleEndian>()? as usize;
+		// The xxhash checksum isn't verified, the NBT parser will fail on corrupt data anyway
+		let _checksum = reader.read_u32::<LittleEndian>()?;
+		if decompressed_length == 0 {
+			return Ok(decompressed);
+		}
+
+		let mut block = vec![0u8; compressed_length];
+		reader.read_exact(&mut block)?;
+		match token & 0xF0 {
+			COMPRESSION_METHOD_RAW => decompressed.extend_from_slice(&block),
+			COMPRESSION_METHOD_LZ4 => decompressed.extend_from_slice(&lz4_flex::block::decompress(&block, decompressed_length)?),
+			method => bail!("Unknown LZ4 block compression method {:#x}", method),
+		}
+	}
+}
+
+/// Finds the c.x.z.mcc file used for a chunk too large to fit in the region file,
+/// using the region coordinates from the r.x.z.mca file name
+fn external_chunk_path(region_path: &Path, index: u16) -> anyhow::Result<PathBuf> {
+	let file_name = region_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+	let coords: Vec<&str> = file_name.split('.').collect();
+	let (region_x, region_z) = match coords.as_slice() {
+		["r", x, z, "mca"] => (x.parse::<i32>()?, z.parse::<i32>()?),
+		_ => bail!(
+			"Can't find external chunks for region file {:?} without a r.x.z.mca name",
+			region_path
+		),
+	};
+	let chunk_x = region_x * 32 + (index % 32) as i32;
+	let chunk_z = region_z * 32 + (index / 32) as i32;
+	Ok(region_path.with_file_name(format!("c.{}.{}.mcc", chunk_x, chunk_z)))
+}
+
+/// Reads an NBT root compound, discarding its name (which is always empty for chunks)
+pub fn read_nbt<R: Read>(src: &mut R) -> anyhow::Result<Value> {
+	let tag = src.read_u8()?;
+	if tag != 0x0a {
+		bail!("Expected a root compound, found tag {}", tag);
+	}
+	let _name = Value::from_reader(0x08, src)?;
+	Ok(Value::from_reader(tag, src)?)
+}
+
+/// Writes an NBT root compound with an empty name
+pub fn write_nbt<W: Write>(dest: &mut W, value: &Value) -> anyhow::Result<()> {
+	dest.write_u8(value.id())?;
+	dest.write_u16::<BigEndian>(0)?;
+	Ok(value.to_writer(dest)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{env, fs, process};
+
+	use flate2::write::GzEncoder;
+	use nbt::Map;
+
+	use super::*;
+
+	fn chunk_data(index: u16) -> Value {
+		let mut root = Map::new();
+		root.insert("xPos".into(), Value::Int(index as i32 % 32));
+		root.insert("zPos".into(), Value::Int(index as i32 / 32));
+		root.insert("Blocks".into(), Value::ByteArray((0..2000).map(|i| (i % 7) as i8).collect()));
+		Value::Compound(root)
+	}
+
+	fn nbt_bytes(data: &Value) -> Vec<u8> {
+		let mut bytes = vec![];
+		write_nbt(&mut bytes, data).unwrap();
+		bytes
+	}
+
+	fn zlib(bytes: &[u8]) -> Vec<u8> {
+		let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+		encoder.write_all(bytes).unwrap();
+		encoder.finish().unwrap()
+	}
+
+	/// The LZ4BlockOutputStream format, with the first half of the bytes in a RAW block and the rest in a compressed block
+	fn lz4_blocks(bytes: &[u8]) -> Vec<u8> {
+		let (raw, rest) = bytes.split_at(bytes.len() / 2);
+		let mut stream = vec![];
+		for (token, block, length) in [
+			(0x10, raw.to_vec(), raw.len()),
+			(0x20, lz4_flex::block::compress(rest), rest.len()),
+			(0x10, vec![], 0),
+		] {
+			stream.extend_from_slice(b"LZ4Block");
+			stream.write_u8(token).unwrap();
+			stream.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+			stream.write_u32::<LittleEndian>(length as u32).unwrap();
+			stream.write_u32::<LittleEndian>(0).unwrap();
+			stream.extend_from_slice(&block);
+		}
+		stream
+	}
+
+	/// Writes a region file with a sector for each chunk, holding its compression type and stored bytes
+	fn write_sectors(path: &Path, chunks: &[(u16, u8, Vec<u8>)]) {
+		let mut header = vec![0u8; 2 * SECTOR_SIZE as usize];
+		let mut sectors = vec![];
+		for (i, (index, compression_type, stored)) in chunks.iter().enumerate() {
+			let location = (2 + i as u32) << 8 | 1;
+			header[*index as usize * 4..][..4].copy_from_slice(&location.to_be_bytes());
+			header[SECTOR_SIZE as usize + *index as usize * 4..][..4].copy_from_slice(&(1000 + *index as u32).to_be_bytes());
+			let mut sector = vec![];
+			sector.write_u32::<BigEndian>(stored.len() as u32 + 1).unwrap();
+			sector.write_u8(*compression_type).unwrap();
+			sector.extend_from_slice(stored);
+			assert!(sector.len() <= SECTOR_SIZE as usize);
+			sector.resize(SECTOR_SIZE as usize, 0);
+			sectors.extend_from_slice(&sector);
+		}
+		header.extend_from_slice(&sectors);
+		fs::write(path, header).unwrap();
+	}
+
+	fn test_dir(name: &str) -> PathBuf {
+		let dir = env::temp_dir().join(format!("miniworld-{}-{}", name, process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn compression_types_are_read() {
+		let dir = test_dir("compression-types");
+		let path = dir.join("r.-1.2.mca");
+		let nbt = |index: u16| nbt_bytes(&chunk_data(index));
+		let mut gzip = GzEncoder::new(vec![], Compression::default());
+		gzip.write_all(&nbt(0)).unwrap();
+		write_sectors(
+			&path,
+			&[
+				(0, CompressionType::Gzip as u8, gzip.finish().unwrap()),
+				(1, CompressionType::Zlib as u8, zlib(&nbt(1))),
+				(2, CompressionType::Uncompressed as u8, nbt(2)),
+				(3, CompressionType::Lz4 as u8, lz4_blocks(&nbt(3))),
+				(33, CompressionType::Zlib as u8 | EXTERNAL_FLAG, vec![]),
+			],
+		);
+		// Chunk 33 of region -1, 2 is chunk -31, 65
+		fs::write(dir.join("c.-31.65.mcc"), zlib(&nbt(33))).unwrap();
+
+		let chunks = read_region(&path).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(chunks.iter().map(|chunk| chunk.index).collect::<Vec<_>>(), [0, 1, 2, 3, 33]);
+		for chunk in &chunks {
+			assert_eq!(chunk.timestamp, 1000 + chunk.index as u32);
+			assert!(chunk.data == chunk_data(chunk.index), "chunk {} differs", chunk.index);
+		}
+	}
+
+	#[test]
+	fn unreadable_chunks_are_errors() {
+		let dir = test_dir("unreadable-chunks");
+		let nbt = nbt_bytes(&chunk_data(0));
+		let mut corrupt_lz4 = lz4_blocks(&nbt);
+		corrupt_lz4[0] = b'X';
+		let mut unknown_method = lz4_blocks(&nbt);
+		unknown_method[8] = 0x30;
+		for (name, compression_type, stored) in [
+			("r.0.0.mca", CompressionType::Lz4 as u8, corrupt_lz4),
+			("r.0.0.mca", CompressionType::Lz4 as u8, unknown_method),
+			("r.0.0.mca", 5, nbt.clone()),
+			// External chunks are found through the region coordinates in the file name
+			("region.mca", CompressionType::Zlib as u8 | EXTERNAL_FLAG, vec![]),
+			("r.0.0.mca", CompressionType::Zlib as u8 | EXTERNAL_FLAG, vec![]),
+		] {
+			let path = dir.join(name);
+			write_sectors(&path, &[(0, compression_type, stored)]);
+			assert!(
+				read_region(&path).is_err(),
+				"{} with compression type {} was read",
+				name,
+				compression_type
+			);
+		}
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn oversized_chunks_are_written_externally() {
+		let dir = test_dir("oversized-chunks");
+		let path = dir.join("r.1.0.mca");
+		let mut data = chunk_data(5);
+		if let Value::Compound(root) = &mut data {
+			// Random enough that it doesn't compress into 255 sectors
+			let mut state = 1u32;
+			let noise = (0..1_200_000)
+				.map(|_| {
+					state ^= state << 13;
+					state ^= state >> 17;
+					state ^= state << 5;
+					state as i8
+				})
+				.collect();
+			root.insert("Noise".into(), Value::ByteArray(noise));
+		}
+		let chunks = vec![
+			Chunk {
+				index: 5,
+				timestamp: 7,
+				data,
+			},
+			Chunk {
+				index: 6,
+				timestamp: 8,
+				data: chunk_data(6),
+			},
+		];
+		write_region(&path, &chunks).unwrap();
+		assert!(dir.join("c.37.0.mcc").exists());
+
+		let read = read_region(&path).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(read.len(), 2);
+		for (read, chunk) in read.iter().zip(&chunks) {
+			assert_eq!((read.index, read.timestamp), (chunk.index, chunk.timestamp));
+			assert!(read.data == chunk.data);
+		}
+	}
+}
@@ -1,17 +1,19 @@
-use nbt::Value;
-use std::collections::BTreeMap;
+use anyhow::{bail, Context};
+use nbt::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use tree_buf::prelude::*;
 
 pub struct NBTStats {
-	map: BTreeMap<String, u32>
+	map: BTreeMap<String, u32>,
 }
 
 impl NBTStats {
 	pub fn new() -> NBTStats {
-		NBTStats{ map: BTreeMap::new() }
+		NBTStats { map: BTreeMap::new() }
 	}
 
-	pub fn accumulate(&mut self, data: &Value) {
-		self.accumulate_internal(data, "".to_string());
+	pub fn accumulate(&mut self, data: &Map<String, Value>) {
+		self.accumulate_compound(data, "".to_string());
 	}
 
 	fn accumulate_internal(&mut self, data: &Value, curr_path: String) {
@@ -28,24 +30,328 @@ impl NBTStats {
 				for value in contents {
 					self.accumulate_internal(value, curr_path.clone())
 				}
-			},
-			Value::Compound(contents) => {
-				for value in contents {
-					match value.1 {
-						Value::List(_) => self.accumulate_internal(value.1, curr_path.clone() + value.0 + "[] -> "),
-						_ => self.accumulate_internal(value.1, curr_path.clone() + value.0 + " -> ")
-					}
-				
-				}
-			},
+			}
+			Value::Compound(contents) => self.accumulate_compound(contents, curr_path),
 			Value::IntArray(_) => *self.map.entry(curr_path).or_insert(0) += 1,
 			Value::LongArray(_) => *self.map.entry(curr_path).or_insert(0) += 1,
 		}
 	}
 
+	fn accumulate_compound(&mut self, contents: &Map<String, Value>, curr_path: String) {
+		for value in contents {
+			match value.1 {
+				Value::List(_) => self.accumulate_internal(value.1, curr_path.clone() + value.0 + "[] -> "),
+				_ => self.accumulate_internal(value.1, curr_path.clone() + value.0 + " -> "),
+			}
+		}
+	}
+
 	pub fn print(&self) {
 		for value in &self.map {
 			println!("{}{}", value.0, value.1);
 		}
 	}
-}
\ No newline at end of file
+}
+
+// Columnar NBT: the values at each path in the NBT of every chunk are stored together, so all xPos are next to each other,
+// then all LastUpdate, then all Entities[].Pos and so on, which tree-buf then encodes column by column.
+// Compounds store which of their key columns they have in which order, so the exact NBT can be rebuilt.
+
+const TAG_BYTE: u8 = 1;
+const TAG_SHORT: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_LONG: u8 = 4;
+const TAG_FLOAT: u8 = 5;
+const TAG_DOUBLE: u8 = 6;
+const TAG_BYTE_ARRAY: u8 = 7;
+const TAG_STRING: u8 = 8;
+const TAG_LIST: u8 = 9;
+const TAG_COMPOUND: u8 = 10;
+const TAG_INT_ARRAY: u8 = 11;
+const TAG_LONG_ARRAY: u8 = 12;
+
+/// The values of one tag type at one path, across every chunk
+#[derive(Encode, Decode, Default)]
+struct Column {
+	/// The key in the parent compound, or None for list elements
+	key: Option<String>,
+	tag: u8,
+	/// Compounds: each distinct sequence of key columns, in key order
+	layouts: Vec<Vec<u64>>,
+	/// Compounds: the layout of each compound. Lists: the element column of each non-empty list.
+	indices: Vec<u64>,
+	/// Lists and arrays: the length of each
+	lengths: Vec<u64>,
+	/// Integers and int and long array contents, zigzag encoded as tree-buf only has unsigned integers
+	integers: Vec<u64>,
+	/// Byte array contents, which are often not small numbers
+	bytes: Vec<u8>,
+	floats: Vec<f32>,
+	doubles: Vec<f64>,
+	strings: Vec<String>,
+}
+
+/// The columns of a list of NBT values, with their roots in the first column
+#[derive(Encode, Decode)]
+struct Columns {
+	columns: Vec<Column>,
+}
+
+fn zigzag(value: i64) -> u64 {
+	((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+	(value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+#[derive(Default)]
+struct ColumnEncoder {
+	columns: Vec<Column>,
+	/// Columns by parent, key and tag
+	ids: HashMap<(usize, Option<String>, u8), usize>,
+	/// Compound layouts by column and key columns
+	layouts: HashMap<(usize, Vec<u64>), u64>,
+}
+
+impl ColumnEncoder {
+	fn column(&mut self, parent: usize, key: Option<&str>, tag: u8) -> usize {
+		let columns = &mut self.columns;
+		*self.ids.entry((parent, key.map(str::to_string), tag)).or_insert_with(|| {
+			columns.push(Column {
+				key: key.map(str::to_string),
+				tag,
+				..Column::default()
+			});
+			columns.len() - 1
+		})
+	}
+
+	fn push(&mut self, id: usize, value: &Value) -> anyhow::Result<()> {
+		match value {
+			Value::Byte(v) => self.columns[id].integers.push(zigzag(*v as i64)),
+			Value::Short(v) => self.columns[id].integers.push(zigzag(*v as i64)),
+			Value::Int(v) => self.columns[id].integers.push(zigzag(*v as i64)),
+			Value::Long(v) => self.columns[id].integers.push(zigzag(*v)),
+			Value::Float(v) => self.columns[id].floats.push(*v),
+			Value::Double(v) => self.columns[id].doubles.push(*v),
+			Value::String(v) => self.columns[id].strings.push(v.clone()),
+			Value::ByteArray(array) => {
+				let column = &mut self.columns[id];
+				column.lengths.push(array.len() as u64);
+				column.bytes.extend(array.iter().map(|v| *v as u8));
+			}
+			Value::IntArray(array) => {
+				let column = &mut self.columns[id];
+				column.lengths.push(array.len() as u64);
+				column.integers.extend(array.iter().map(|v| zigzag(*v as i64)));
+			}
+			Value::LongArray(array) => {
+				let column = &mut self.columns[id];
+				column.lengths.push(array.len() as u64);
+				column.integers.extend(array.iter().map(|v| zigzag(*v)));
+			}
+			Value::List(list) => {
+				self.columns[id].lengths.push(list.len() as u64);
+				if let Some(first) = list.first() {
+					let element_id = self.column(id, None, first.id());
+					self.columns[id].indices.push(element_id as u64);
+					for element in list {
+						if element.id() != first.id() {
+							bail!("List with both {} and {} elements", first.tag_name(), element.tag_name());
+						}
+						self.push(element_id, element)?;
+					}
+				}
+			}
+			Value::Compound(compound) => {
+				let layout: Vec<u64> = compound
+					.iter()
+					.map(|(key, child)| self.column(id, Some(key), child.id()) as u64)
+					.collect();
+				let layout_count = self.columns[id].layouts.len() as u64;
+				let layout_index = *self.layouts.entry((id, layout.clone())).or_insert(layout_count);
+				if layout_index == layout_count {
+					self.columns[id].layouts.push(layout.clone());
+				}
+				self.columns[id].indices.push(layout_index);
+				for (child, child_id) in compound.values().zip(layout) {
+					self.push(child_id as usize, child)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// How far into each of a column's value lists decoding has got
+#[derive(Default, Clone)]
+struct Positions {
+	indices: usize,
+	lengths: usize,
+	integers: usize,
+	bytes: usize,
+	floats: usize,
+	doubles: usize,
+	strings: usize,
+}
+
+struct ColumnDecoder {
+	columns: Vec<Column>,
+	positions: Vec<Positions>,
+}
+
+fn next<T: Clone>(values: &[T], position: &mut usize) -> anyhow::Result<T> {
+	let value = values.get(*position).cloned().context("NBT column ended early")?;
+	*position += 1;
+	Ok(value)
+}
+
+impl ColumnDecoder {
+	fn integer(&mut self, id: usize) -> anyhow::Result<i64> {
+		Ok(unzigzag(next(&self.columns[id].integers, &mut self.positions[id].integers)?))
+	}
+
+	fn integers(&mut self, id: usize) -> anyhow::Result<Vec<i64>> {
+		let length = next(&self.columns[id].lengths, &mut self.positions[id].lengths)?;
+		(0..length).map(|_| self.integer(id)).collect()
+	}
+
+	fn value(&mut self, id: usize) -> anyhow::Result<Value> {
+		let column = self.columns.get(id).context("Missing NBT column")?;
+		let positions = &mut self.positions[id];
+		Ok(match column.tag {
+			TAG_BYTE => Value::Byte(self.integer(id)? as i8),
+			TAG_SHORT => Value::Short(self.integer(id)? as i16),
+			TAG_INT => Value::Int(self.integer(id)? as i32),
+			TAG_LONG => Value::Long(self.integer(id)?),
+			TAG_FLOAT => Value::Float(next(&column.floats, &mut positions.floats)?),
+			TAG_DOUBLE => Value::Double(next(&column.doubles, &mut positions.doubles)?),
+			TAG_STRING => Value::String(next(&column.strings, &mut positions.strings)?),
+			TAG_BYTE_ARRAY => {
+				let length = next(&column.lengths, &mut positions.lengths)?;
+				(0..length)
+					.map(|_| next(&column.bytes, &mut positions.bytes).map(|v| v as i8))
+					.collect::<anyhow::Result<_>>()
+					.map(Value::ByteArray)?
+			}
+			TAG_INT_ARRAY => Value::IntArray(self.integers(id)?.into_iter().map(|v| v as i32).collect()),
+			TAG_LONG_ARRAY => Value::LongArray(self.integers(id)?),
+			TAG_LIST => {
+				let length = next(&column.lengths, &mut positions.lengths)?;
+				if length == 0 {
+					Value::List(vec![])
+				} else {
+					let element_id = next(&column.indices, &mut positions.indices)? as usize;
+					Value::List((0..length).map(|_| self.value(element_id)).collect::<anyhow::Result<_>>()?)
+				}
+			}
+			TAG_COMPOUND => {
+				let layout_index = next(&column.indices, &mut positions.indices)? as usize;
+				let layout = column
+					.layouts
+					.get(layout_index)
+					.context("Missing NBT compound layout")?
+					.clone();
+				let mut compound = Map::new();
+				for child_id in layout {
+					let key = self
+						.columns
+						.get(child_id as usize)
+						.and_then(|child| child.key.clone())
+						.context("Missing NBT key")?;
+					compound.insert(key, self.value(child_id as usize)?);
+				}
+				Value::Compound(compound)
+			}
+			tag => bail!("Unknown NBT tag {}", tag),
+		})
+	}
+}
+
+/// Encodes NBT values column by column with tree-buf
+pub fn encode_columns(values: &[Value]) -> anyhow::Result<Vec<u8>> {
+	let mut encoder = ColumnEncoder::default();
+	for value in values {
+		let root_id = encoder.column(0, None, value.id());
+		if root_id != 0 {
+			bail!("NBT roots with different tags");
+		}
+		encoder.push(root_id, value)?;
+	}
+	Ok(tree_buf::encode(&Columns {
+		columns: encoder.columns,
+	}))
+}
+
+/// Rebuilds the given number of NBT values from their columns
+pub fn decode_columns(data: &[u8], count: usize) -> anyhow::Result<Vec<Value>> {
+	let Columns { columns } = tree_buf::decode(data)?;
+	let mut decoder = ColumnDecoder {
+		positions: vec![Positions::default(); columns.len()],
+		columns,
+	};
+	(0..count).map(|_| decoder.value(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn compound(entries: Vec<(&str, Value)>) -> Value {
+		Value::Compound(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+	}
+
+	fn chunk(i: i32) -> Value {
+		let mut entries = vec![
+			("DataVersion", Value::Int(2975)),
+			("xPos", Value::Int(-i)),
+			("Status", Value::String(format!("status{}", i))),
+			("LastUpdate", Value::Long(i64::MIN + i as i64)),
+			("Byte", Value::Byte(-(i as i8))),
+			("Short", Value::Short(i as i16 * 1000)),
+			("Float", Value::Float(i as f32 / 3.0)),
+			("Double", Value::Double(-(i as f64) / 7.0)),
+			("Bytes", Value::ByteArray((0..i).map(|v| v as i8 - 3).collect())),
+			("Ints", Value::IntArray(vec![i32::MAX, i32::MIN, i])),
+			("Longs", Value::LongArray((0..i).map(|v| v as i64 * -99).collect())),
+			("Empty", Value::List(vec![])),
+			(
+				"Sections",
+				Value::List(
+					(0..i)
+						.map(|y| compound(vec![("Y", Value::Byte(y as i8)), ("Palette", Value::List(vec![]))]))
+						.collect(),
+				),
+			),
+		];
+		// Compounds with different keys and key orders get different layouts
+		if i % 2 == 0 {
+			entries.swap(1, 2);
+			entries.push((
+				"Extra",
+				compound(vec![("Nested", Value::List(vec![Value::Int(i), Value::Int(0)]))]),
+			));
+		}
+		compound(entries)
+	}
+
+	#[test]
+	fn columns_round_trip() {
+		let values: Vec<Value> = (0..5).map(chunk).collect();
+		let encoded = encode_columns(&values).unwrap();
+		assert_eq!(decode_columns(&encoded, values.len()).unwrap(), values);
+	}
+
+	#[test]
+	fn mixed_lists_are_an_error() {
+		assert!(encode_columns(&[Value::List(vec![Value::Int(0), Value::Long(0)])]).is_err());
+	}
+
+	#[test]
+	fn decoding_too_many_values_is_an_error() {
+		let values: Vec<Value> = (0..2).map(chunk).collect();
+		let encoded = encode_columns(&values).unwrap();
+		assert!(decode_columns(&encoded, 3).is_err());
+	}
+}
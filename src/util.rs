@@ -1,13 +1,53 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+/// How integers are laid out in a packed long array
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Packing {
+	/// Values never straddle two longs, leaving the top bits of each long unused (1.16 onwards)
+	Padded,
+	/// Values are packed contiguously and may be split across two longs (1.13 to 1.15)
+	Spanning,
+}
+
+/// The shape of an array of values ordered like block states: X varies fastest, then Z, then Y.
+/// 2D arrays such as heightmaps have a height of 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Dimensions {
+	pub x: usize,
+	pub y: usize,
+	pub z: usize,
+}
+
+impl Dimensions {
+	/// Block states and light in a section
+	pub const SECTION: Dimensions = Dimensions { x: 16, y: 16, z: 16 };
+	/// Biomes in a 1.18 section
+	pub const SECTION_BIOMES: Dimensions = Dimensions { x: 4, y: 4, z: 4 };
+
+	/// The number of values
+	pub fn volume(self) -> usize {
+		self.x * self.y * self.z
+	}
+
+	/// The x, y and z coordinates of an index
+	pub fn coords(self, i: usize) -> (usize, usize, usize) {
+		(i % self.x, i / (self.x * self.z), (i / self.x) % self.z)
+	}
+}
+
 pub struct PackedIntegerArrayIter<'a, I: Iterator<Item = &'a i64>> {
 	inner: I,
 	curr_value: u64,
 	curr_offset: u8,
 	num_bits: u8,
 	bitmask: u64,
+	packing: Packing,
 }
 
 impl<'a, I: Iterator<Item = &'a i64>> PackedIntegerArrayIter<'a, I> {
-	pub fn new(iter: I, num_bits: u8) -> PackedIntegerArrayIter<'a, I> {
+	pub fn new(iter: I, num_bits: u8, packing: Packing) -> PackedIntegerArrayIter<'a, I> {
 		assert!(num_bits > 0, "Number of bits per integer must be greater than 0");
 		assert!(num_bits <= 32, "Number of bits per integer must not exceed 32");
 		PackedIntegerArrayIter {
@@ -15,7 +55,8 @@ impl<'a, I: Iterator<Item = &'a i64>> PackedIntegerArrayIter<'a, I> {
 			curr_value: 0,
 			curr_offset: 0,
 			num_bits,
-			bitmask: (1 << num_bits) - 1
+			bitmask: (1 << num_bits) - 1,
+			packing,
 		}
 	}
 }
@@ -29,12 +70,170 @@ impl<'a, I: Iterator<Item = &'a i64>> Iterator for PackedIntegerArrayIter<'a, I>
 			self.curr_value = *self.inner.next()? as u64;
 		}
 		// Shift to get the value, mask it to get only the bits we want
-		let value = ((self.curr_value >> self.curr_offset) & self.bitmask) as u32;
+		let mut value = (self.curr_value >> self.curr_offset) & self.bitmask;
 		// Move to the next value
 		self.curr_offset += self.num_bits;
-		if self.curr_offset == (64 - (64 % self.num_bits)) {
-			self.curr_offset = 0;
+		match self.packing {
+			Packing::Padded => {
+				if self.curr_offset == (64 - (64 % self.num_bits)) {
+					self.curr_offset = 0;
+				}
+			}
+			Packing::Spanning => {
+				if self.curr_offset >= 64 {
+					self.curr_offset -= 64;
+					// The high bits of the value are at the start of the next long
+					if self.curr_offset > 0 {
+						self.curr_value = *self.inner.next()? as u64;
+						value |= (self.curr_value << (self.num_bits - self.curr_offset)) & self.bitmask;
+					}
+				}
+			}
+		}
+		Some(value as u32)
+	}
+}
+
+/// Packs integers into longs in the same layout read by PackedIntegerArrayIter
+pub fn pack_integers(values: &[u32], num_bits: u8, packing: Packing) -> Vec<i64> {
+	assert!(num_bits > 0, "Number of bits per integer must be greater than 0");
+	assert!(num_bits <= 32, "Number of bits per integer must not exceed 32");
+	let num_bits = num_bits as usize;
+	match packing {
+		Packing::Padded => values
+			.chunks(64 / num_bits)
+			.map(|chunk| {
+				let mut packed = 0u64;
+				for (i, value) in chunk.iter().enumerate() {
+					packed |= (*value as u64) << (i * num_bits);
+				}
+				packed as i64
+			})
+			.collect(),
+		Packing::Spanning => {
+			let mut packed = vec![0u64; (values.len() * num_bits).div_ceil(64)];
+			for (i, value) in values.iter().enumerate() {
+				let index = i * num_bits / 64;
+				let offset = i * num_bits % 64;
+				packed[index] |= (*value as u64) << offset;
+				if offset + num_bits > 64 {
+					packed[index + 1] |= (*value as u64) >> (64 - offset);
+				}
+			}
+			packed.into_iter().map(|v| v as i64).collect()
+		}
+	}
+}
+
+/// The number of bits used to store a block state palette index, with a minimum of 4
+pub fn palette_bits(palette_length: u32) -> u8 {
+	match (palette_length as f64).log2().ceil() as u8 {
+		0..=4 => 4,
+		x => x,
+	}
+}
+
+/// The number of bits used to store a 1.18 biome palette index, which has no minimum
+pub fn biome_bits(palette_length: u32) -> u8 {
+	(palette_length as f64).log2().ceil() as u8
+}
+
+pub fn write_varint<W: Write>(dest: &mut W, mut value: u64) -> io::Result<()> {
+	loop {
+		let byte = (value & 0b0111_1111) as u8;
+		value >>= 7;
+		if value == 0 {
+			return dest.write_u8(byte);
+		}
+		dest.write_u8(byte | 0b1000_0000)?;
+	}
+}
+
+pub fn read_varint<R: Read>(src: &mut R) -> io::Result<u64> {
+	let mut value = 0u64;
+	let mut shift = 0;
+	loop {
+		let byte = src.read_u8()?;
+		value |= ((byte & 0b0111_1111) as u64) << shift;
+		if byte & 0b1000_0000 == 0 {
+			return Ok(value);
+		}
+		shift += 7;
+		if shift >= 64 {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "Varint is too long"));
+		}
+	}
+}
+
+/// Writes a signed value as a varint, with small negative values as short as small positive ones
+pub fn write_signed_varint<W: Write>(dest: &mut W, value: i64) -> io::Result<()> {
+	write_varint(dest, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+pub fn read_signed_varint<R: Read>(src: &mut R) -> io::Result<i64> {
+	let value = read_varint(src)?;
+	Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn pack_round_trip(num_bits: u8, packing: Packing) {
+		let values: Vec<u32> = (0..4096u32)
+			.map(|i| i.wrapping_mul(2_654_435_761) >> (32 - num_bits))
+			.collect();
+		let packed = pack_integers(&values, num_bits, packing);
+		let unpacked: Vec<u32> = PackedIntegerArrayIter::new(packed.iter(), num_bits, packing)
+			.take(values.len())
+			.collect();
+		assert_eq!(values, unpacked);
+	}
+
+	#[test]
+	fn spanning_packing_round_trips() {
+		// 5, 6, 7 and 12 bits don't divide 64, so values straddle two longs
+		for num_bits in [1, 4, 5, 6, 7, 12, 32] {
+			pack_round_trip(num_bits, Packing::Spanning);
+		}
+		assert_eq!(pack_integers(&[0; 4096], 5, Packing::Spanning).len(), 320);
+	}
+
+	#[test]
+	fn padded_packing_round_trips() {
+		for num_bits in [1, 4, 5, 6, 7, 12, 32] {
+			pack_round_trip(num_bits, Packing::Padded);
+		}
+		// 12 values of 5 bits fit in each long
+		assert_eq!(pack_integers(&[0; 4096], 5, Packing::Padded).len(), 342);
+	}
+
+	#[test]
+	fn spanning_values_are_split_across_longs() {
+		// The 13th 5 bit value starts at bit 60, so its low 4 bits end the first long and its high bit starts the second
+		let mut values = [0u32; 26];
+		values[12] = 0b11111;
+		let packed = pack_integers(&values, 5, Packing::Spanning);
+		assert_eq!(packed, vec![0xF000_0000_0000_0000u64 as i64, 1, 0]);
+	}
+
+	#[test]
+	fn varints_round_trip() {
+		let mut bytes = vec![];
+		for value in [0, 1, 127, 128, u64::MAX] {
+			write_varint(&mut bytes, value).unwrap();
+		}
+		for value in [0, -1, 1, i64::MIN, i64::MAX] {
+			write_signed_varint(&mut bytes, value).unwrap();
+		}
+		let mut src = Cursor::new(bytes);
+		for value in [0, 1, 127, 128, u64::MAX] {
+			assert_eq!(read_varint(&mut src).unwrap(), value);
+		}
+		for value in [0, -1, 1, i64::MIN, i64::MAX] {
+			assert_eq!(read_signed_varint(&mut src).unwrap(), value);
 		}
-		Some(value)
 	}
 }